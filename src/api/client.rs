@@ -0,0 +1,200 @@
+//! The HTTP clients used to call the providers, built once per distinct setting
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use parking_lot::Mutex;
+use reqwest::{Certificate, Client, ClientBuilder, Identity, NoProxy, Proxy, Url};
+
+use super::uni_ollama::config::{TlsConfig, UniModelsInfo};
+
+impl TlsConfig {
+    /// Load the certificates and set up `builder` with them
+    pub(crate) fn apply(
+        &self,
+        mut builder: ClientBuilder,
+    ) -> anyhow::Result<ClientBuilder> {
+        for path in &self.ca_certs {
+            let pem = std::fs::read(path).with_context(|| format!("Read {path:?}"))?;
+            for cert in Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Parse {path:?}"))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem =
+                    std::fs::read(cert).with_context(|| format!("Read {cert:?}"))?;
+                pem.push(b'\n');
+                pem.extend(std::fs::read(key).with_context(|| format!("Read {key:?}"))?);
+                let identity = Identity::from_pem(&pem)
+                    .with_context(|| format!("Parse {cert:?} and {key:?}"))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("client_cert and client_key go together"),
+        }
+        for (host, ips) in &self.resolve {
+            // The port is taken from the url
+            let addrs = ips
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, 0))
+                .collect::<Vec<_>>();
+            builder = builder.resolve_to_addrs(host, &addrs);
+        }
+        Ok(builder)
+    }
+
+    /// `url` with [`Self::sni`] as its host,
+    /// and the settings that still connect to its original host, which must be an IP address
+    pub(crate) fn pin_sni(&self, url: &str) -> anyhow::Result<(String, TlsConfig)> {
+        let mut tls = self.clone();
+        let Some(sni) = &self.sni else {
+            return Ok((url.to_string(), tls));
+        };
+        let mut url = Url::parse(url).with_context(|| format!("Invalid url {url}"))?;
+        let Some(ip) = sni_target(&url) else {
+            bail!("tls.sni needs an IP address as the host of {url}");
+        };
+        url.set_host(Some(sni))
+            .with_context(|| format!("Invalid sni {sni}"))?;
+        tls.resolve.insert(sni.clone(), vec![ip]);
+        Ok((url.to_string(), tls))
+    }
+}
+
+/// The address a url pinned with [`TlsConfig::sni`] connects to, its host if it is an IP address.
+/// A host name could resolve elsewhere than the name presented instead of it
+pub(crate) fn sni_target(url: &Url) -> Option<IpAddr> {
+    url.host_str()?.trim_matches(['[', ']']).parse().ok()
+}
+
+/// The settings a client is built with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    /// See [`UniModelsInfo::proxy_of`]
+    proxy_url: Option<String>,
+    tls: Option<TlsConfig>,
+    connect_timeout: Duration,
+}
+
+#[derive(Default)]
+struct Clients {
+    /// See [`UniModelsInfo::no_proxy`]
+    no_proxy: Vec<String>,
+    clients: HashMap<ClientKey, Client>,
+    /// Counts the reconfigurations, to tell a client built before the latest one
+    generation: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct ClientCache {
+    inner: Mutex<Clients>,
+}
+
+impl ClientCache {
+    pub(crate) fn new(config: &UniModelsInfo) -> Self {
+        let cache = Self::default();
+        cache.reconfigure(config);
+        cache
+    }
+
+    /// Drop the clients of the proxies `config` no longer uses,
+    /// and every client if the hosts bypassing the proxies changed.
+    /// The clients with a [`TlsConfig`] are always dropped, so that its certificates are read again
+    pub(crate) fn reconfigure(&self, config: &UniModelsInfo) {
+        let proxies = config
+            .api_keys
+            .values()
+            .map(|info| info.proxy_url.as_ref())
+            .chain(config.models.values().map(|model| model.proxy_url.as_ref()))
+            .chain([config.proxy_url.as_ref(), None])
+            .collect::<HashSet<_>>();
+        let mut guard = self.inner.lock();
+        guard.generation += 1;
+        if guard.no_proxy != config.no_proxy {
+            guard.no_proxy.clone_from(&config.no_proxy);
+            guard.clients.clear();
+        }
+        guard.clients.retain(|key, _| {
+            key.tls.is_none() && proxies.contains(&key.proxy_url.as_ref())
+        });
+    }
+
+    /// Get the client with the given settings, building it on first use.
+    /// It is built without the lock held, as that reads the certificates of `tls`
+    pub(crate) fn get(
+        &self,
+        proxy_url: Option<&str>,
+        tls: Option<&TlsConfig>,
+        connect_timeout: Duration,
+    ) -> anyhow::Result<Client> {
+        let key = ClientKey {
+            proxy_url: proxy_url.map(str::to_string),
+            tls: tls.cloned(),
+            connect_timeout,
+        };
+        let (no_proxy, generation) = {
+            let guard = self.inner.lock();
+            if let Some(client) = guard.clients.get(&key) {
+                return Ok(client.clone());
+            }
+            (guard.no_proxy.clone(), guard.generation)
+        };
+        let mut builder = ClientBuilder::new().connect_timeout(connect_timeout);
+        if let Some(tls) = tls {
+            builder = tls.apply(builder)?;
+        }
+        let builder = match proxy_url {
+            Some(url) => {
+                // The url is left out of the error, as it may hold credentials
+                let proxy = Proxy::all(url)
+                    .context("Invalid proxy url")?
+                    .no_proxy(NoProxy::from_string(&no_proxy.join(",")));
+                builder.proxy(proxy)
+            }
+            None => builder.no_proxy(),
+        };
+        let client = builder.build().context("Construct client")?;
+        let mut guard = self.inner.lock();
+        // Not kept if the config changed meanwhile, the next request builds it again
+        if guard.generation != generation {
+            return Ok(client);
+        }
+        // Another request may have built it meanwhile, they all share the first one
+        Ok(guard.clients.entry(key).or_insert(client).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::api::uni_ollama::config::TlsConfig;
+
+    #[test]
+    fn test_pin_sni() {
+        let tls = TlsConfig {
+            sni: Some("gateway.corp".to_string()),
+            ..Default::default()
+        };
+        let (url, pinned) = tls.pin_sni("https://10.0.0.5:8443/v1/chat").unwrap();
+        assert_eq!(url, "https://gateway.corp:8443/v1/chat");
+        let ip: IpAddr = "10.0.0.5".parse().unwrap();
+        assert_eq!(pinned.resolve["gateway.corp"], [ip]);
+
+        let (url, pinned) = tls.pin_sni("https://[::1]/v1").unwrap();
+        assert_eq!(url, "https://gateway.corp/v1");
+        assert_eq!(
+            pinned.resolve["gateway.corp"],
+            ["::1".parse::<IpAddr>().unwrap()]
+        );
+
+        // A host name would connect wherever the sni resolves to
+        assert!(tls.pin_sni("https://gateway.example/v1").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! A fake OpenAI compatible provider and the shared state around it, for the tests
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use crate::{
+    api::uni_ollama::{message::OllamaChatRequest, reload::ConfigFile},
+    common::tokenizer::{get_tokenizer, UsageEstimator},
+    new_state, ApiKeyInfo, ApiKeyProvider, ModelInfo, SharedStateRef, TokenizerKind,
+    UniModelsInfo,
+};
+
+/// A provider answering every chat completion request with `handler`
+pub(crate) struct MockProvider {
+    /// The full url of its chat completion endpoint
+    pub url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockProvider {
+    pub(crate) async fn start<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Value) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let app = Router::new().fallback(move |Json(body): Json<Value>| {
+            recorded.lock().push(body.clone());
+            handler(body)
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/chat/completions", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, requests }
+    }
+
+    /// The bodies of the requests received so far
+    pub(crate) fn requests(&self) -> Vec<Value> {
+        self.requests.lock().clone()
+    }
+
+    /// An api_key sending its requests to this provider
+    pub(crate) fn api_key(&self) -> ApiKeyInfo {
+        api_key(&self.url)
+    }
+}
+
+/// An api_key sending its requests to `url`
+pub(crate) fn api_key(url: &str) -> ApiKeyInfo {
+    ApiKeyInfo {
+        api_key: vec!["sk-test".to_string()],
+        provider: ApiKeyProvider::Custom(url.to_string()),
+        ..Default::default()
+    }
+}
+
+/// A url nothing listens on, so that connecting to it fails
+pub(crate) async fn closed_url() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("http://{}/chat/completions", listener.local_addr().unwrap())
+}
+
+/// A complete answer of `content`
+pub(crate) fn completion(content: &str) -> Response {
+    Json(json!({
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop",
+        }],
+        "usage": { "prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2 },
+    }))
+    .into_response()
+}
+
+fn chunk(content: &str) -> Bytes {
+    let data = json!({
+        "choices": [{ "index": 0, "delta": { "role": "assistant", "content": content } }],
+    });
+    Bytes::from(format!("data: {data}\n\n"))
+}
+
+/// A streamed answer made of `chunks`
+pub(crate) fn stream(chunks: &[&str]) -> Response {
+    let mut body = chunks.iter().map(|c| chunk(c)).collect::<Vec<_>>();
+    body.push(Bytes::from("data: [DONE]\n\n"));
+    event_stream(Body::from_stream(futures::stream::iter(
+        body.into_iter().map(Ok::<_, std::io::Error>),
+    )))
+}
+
+/// A stream of `chunks` that breaks before it is done
+pub(crate) fn broken_stream(chunks: &[&str]) -> Response {
+    let chunks = chunks.iter().map(|c| Ok(chunk(c))).collect::<Vec<_>>();
+    // The chunks are sent before it breaks
+    let broken = futures::stream::once(async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        Err(std::io::Error::other("mock break"))
+    });
+    event_stream(Body::from_stream(
+        futures::stream::iter(chunks).chain(broken),
+    ))
+}
+
+fn event_stream(body: Body) -> Response {
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .body(body)
+        .unwrap()
+}
+
+/// An error response with `status`
+pub(crate) fn error(status: StatusCode) -> Response {
+    (
+        status,
+        Json(json!({ "error": { "message": "mock error" } })),
+    )
+        .into_response()
+}
+
+/// A model named `name` served by `api_key_id`
+pub(crate) fn model(name: &str, api_key_id: &str) -> ModelInfo {
+    ModelInfo {
+        name: name.to_string(),
+        api_key_id: api_key_id.to_string(),
+        ..Default::default()
+    }
+}
+
+/// A chat request to `model` saying hello
+pub(crate) fn request(model: &str, stream: bool) -> OllamaChatRequest {
+    serde_json::from_value(json!({
+        "model": model,
+        "messages": [{ "role": "user", "content": "hello" }],
+        "stream": stream,
+    }))
+    .unwrap()
+}
+
+/// An estimator for requests made with [`request`]
+pub(crate) fn estimator() -> UsageEstimator {
+    let tokenizer = get_tokenizer(&TokenizerKind::Heuristic).unwrap();
+    UsageEstimator::new(tokenizer, 1)
+}
+
+/// The state of a server running `config`, which has no secret reference
+pub(crate) fn test_state(config: UniModelsInfo) -> SharedStateRef {
+    new_state(config.clone(), ConfigFile::new(None, config, None))
+}
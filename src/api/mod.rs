@@ -1,3 +1,6 @@
+pub(crate) mod client;
 pub(crate) mod common;
+#[cfg(test)]
+pub(crate) mod mock;
 pub(crate) mod provider;
 pub(crate) mod uni_ollama;
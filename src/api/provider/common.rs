@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use anyhow::{bail, Context};
+use anyhow::Context;
 use axum::{
     body::Body,
     http::{HeaderMap, HeaderValue},
@@ -17,28 +17,28 @@ use crate::{
     api::uni_ollama::message::{
         gen_last_message, OllamaChatRequest, ReqMessage, RespMessage, Tool,
     },
-    common::stream::get_ollama_stream,
+    common::{stream::get_ollama_stream, tokenizer::UsageEstimator},
 };
 
-use super::message::{ApiResponse, Usage};
+use super::{error::UpstreamError, message::ApiResponse};
 
 #[derive(Debug, Serialize)]
-pub(crate) struct CommonReq {
-    pub model: String,
-    pub messages: Vec<ReqMessage>,
+pub(crate) struct CommonReq<'a> {
+    pub model: &'a str,
+    pub messages: &'a [ReqMessage],
     pub stream: bool,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "<[Tool]>::is_empty")]
+    pub tools: &'a [Tool],
 }
 
-pub(crate) async fn chat_completion<U: IntoUrl + Debug>(
+/// Send `chat_req` to an OpenAI compatible chat completion api
+pub(crate) async fn send_request<U: IntoUrl + Debug>(
     url: U,
-    chat_req: OllamaChatRequest,
-    model_id: String,
-    model_name: String,
-    api_key: String,
-    client: Client,
-) -> anyhow::Result<Response> {
+    chat_req: &OllamaChatRequest,
+    model_name: &str,
+    api_key: &str,
+    client: &Client,
+) -> anyhow::Result<reqwest::Response> {
     let mut headers = HeaderMap::new();
     let api_key = format!("Bearer {}", api_key);
     headers.insert(AUTHORIZATION, HeaderValue::from_str(&api_key)?);
@@ -51,18 +51,18 @@ pub(crate) async fn chat_completion<U: IntoUrl + Debug>(
     // Construct request body
     let req = CommonReq {
         model: model_name,
-        messages: chat_req.messages,
+        messages: &chat_req.messages,
         stream: chat_req.stream,
-        tools: chat_req.tools,
+        tools: &chat_req.tools,
     };
     let mut body = serde_json::to_value(&req).context("construct common req")?;
 
-    if let Some(options) = chat_req.options {
+    if let Some(options) = &chat_req.options {
         // TODO: Insert options based on [doc](https://api-docs.deepseek.com/zh-cn/api/create-chat-completion)
-        options.into_iter().for_each(|(k, v)| {
+        options.iter().for_each(|(k, v)| {
             body.as_object_mut()
                 .expect("as object nerver fails")
-                .insert(k, v);
+                .insert(k.clone(), v.clone());
         });
     }
 
@@ -73,31 +73,39 @@ pub(crate) async fn chat_completion<U: IntoUrl + Debug>(
         .headers(headers)
         .json(&body)
         .send()
-        .await?;
+        .await
+        .map_err(UpstreamError::Request)?;
 
     // Check response status
     if !api_resp.status().is_success() {
-        let error_text = api_resp.text().await?;
-        tracing::error!("Failed to request API: {}", error_text);
-        bail!("error:{error_text}")
+        return Err(UpstreamError::from_response(api_resp).await.into());
     }
+    Ok(api_resp)
+}
 
-    // Process api response
-    if chat_req.stream {
-        process_streaming(model_id, api_resp).await
+/// Convert the response of an OpenAI compatible chat completion api into ollama format
+pub(crate) async fn process_response(
+    model_id: String,
+    stream: bool,
+    api_resp: reqwest::Response,
+    estimator: UsageEstimator,
+) -> anyhow::Result<Response> {
+    if stream {
+        process_streaming(model_id, api_resp, estimator).await
     } else {
-        process_non_streaming(model_id, api_resp).await
+        process_non_streaming(model_id, api_resp, estimator).await
     }
 }
 
-#[instrument(skip(api_resp))]
+#[instrument(skip(api_resp, estimator))]
 async fn process_streaming(
     model_id: String,
     api_resp: reqwest::Response,
+    estimator: UsageEstimator,
 ) -> anyhow::Result<Response> {
     let stream = api_resp.bytes_stream();
 
-    let ollama_resp_stream = get_ollama_stream(model_id, stream);
+    let ollama_resp_stream = get_ollama_stream(model_id, stream, estimator);
     let mut header = HeaderMap::new();
     header.append(
         CONTENT_TYPE,
@@ -111,10 +119,11 @@ async fn process_streaming(
     Ok(res)
 }
 
-#[instrument(skip(api_resp))]
+#[instrument(skip(api_resp, estimator))]
 async fn process_non_streaming(
     model_id: String,
     api_resp: reqwest::Response,
+    estimator: UsageEstimator,
 ) -> anyhow::Result<Response> {
     let api_resp = api_resp
         .json::<ApiResponse>()
@@ -132,6 +141,7 @@ async fn process_non_streaming(
         content.push_str("</think>\n");
     }
     content.push_str(&delta.content);
+    let usage = estimator.fill(api_resp.usage, &content);
 
     let ollama_resp = gen_last_message(
         &model_id,
@@ -140,7 +150,7 @@ async fn process_non_streaming(
             content,
             images: None,
         }),
-        api_resp.usage.as_ref().unwrap_or(&Usage::default()),
+        &usage,
         0,
     );
     tracing::debug!("response_body:{ollama_resp}");
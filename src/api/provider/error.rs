@@ -0,0 +1,187 @@
+use std::{
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{header::RETRY_AFTER, StatusCode};
+
+/// An error of a request made to a provider, kept apart from other errors
+/// to decide whether the request can be sent to another target
+#[derive(Debug)]
+pub(crate) enum UpstreamError {
+    /// Failed to send the request
+    Request(reqwest::Error),
+    /// The provider responded with an error status
+    Status {
+        status: StatusCode,
+        /// How long the provider asks us to wait, from `Retry-After` or `x-ratelimit-reset`
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    /// Every api_key of the `api_key_id` is disabled or cooling down
+    Unavailable(String),
+    /// The provider took longer than the configured timeout
+    Timeout(TimeoutKind, Duration),
+    /// The queue for a free slot of the `api_key_id` is full
+    QueueFull(String),
+}
+
+/// Which of the [`crate::TimeoutConfig`] timeouts expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeoutKind {
+    Connect,
+    FirstByte,
+    Idle,
+    Total,
+    /// Waiting for a free slot of [`crate::ConcurrencyConfig`]
+    Queue,
+}
+
+impl Display for TimeoutKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::FirstByte => "first byte",
+            TimeoutKind::Idle => "idle stream",
+            TimeoutKind::Total => "total",
+            TimeoutKind::Queue => "queue",
+        };
+        f.write_str(kind)
+    }
+}
+
+impl UpstreamError {
+    /// Read the error status of `api_resp`
+    pub(crate) async fn from_response(api_resp: reqwest::Response) -> Self {
+        let status = api_resp.status();
+        let headers = api_resp.headers();
+        let retry_after = [RETRY_AFTER.as_str(), "x-ratelimit-reset"]
+            .into_iter()
+            .filter_map(|name| headers.get(name)?.to_str().ok())
+            .find_map(parse_retry_after);
+        match api_resp.text().await {
+            Ok(body) => {
+                tracing::error!("Failed to request API: {status} {body}");
+                UpstreamError::Status {
+                    status,
+                    retry_after,
+                    body,
+                }
+            }
+            Err(e) => UpstreamError::Request(e),
+        }
+    }
+
+    /// Connect errors, rate limits and server errors are worth trying elsewhere
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            UpstreamError::Request(e) => e.is_connect() || e.is_timeout(),
+            UpstreamError::Status { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+            UpstreamError::Unavailable(_)
+            | UpstreamError::Timeout(..)
+            | UpstreamError::QueueFull(_) => true,
+        }
+    }
+
+    /// The [`UpstreamError`] behind `err`, also when it is shared by coalesced requests
+    pub(crate) fn of(err: &anyhow::Error) -> Option<&UpstreamError> {
+        err.downcast_ref::<UpstreamError>()
+            .or_else(|| err.downcast_ref::<Arc<UpstreamError>>().map(|e| &**e))
+    }
+
+    /// Whether `err` is an [`UpstreamError`] worth trying elsewhere
+    pub(crate) fn is_retryable_error(err: &anyhow::Error) -> bool {
+        UpstreamError::of(err).is_some_and(UpstreamError::is_retryable)
+    }
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Request(e) => write!(f, "error:{e}"),
+            UpstreamError::Status { status, body, .. } => {
+                write!(f, "error:{status} {body}")
+            }
+            UpstreamError::Unavailable(api_key_id) => {
+                write!(f, "error:every api_key of {api_key_id} is unavailable")
+            }
+            UpstreamError::Timeout(kind, timeout) => {
+                write!(f, "error:{kind} timeout after {timeout:?}")
+            }
+            UpstreamError::QueueFull(api_key_id) => {
+                write!(f, "error:the queue of {api_key_id} is full")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpstreamError::Request(e) => Some(e),
+            UpstreamError::Status { .. }
+            | UpstreamError::Unavailable(_)
+            | UpstreamError::Timeout(..)
+            | UpstreamError::QueueFull(_) => None,
+        }
+    }
+}
+
+/// Parse the delay of a `Retry-After` or `x-ratelimit-reset` header, which can be
+/// seconds (`"30"`), a unix timestamp, an HTTP date or a duration (`"6m0s"`, `"20ms"`)
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        // A timestamp rather than a number of seconds
+        if secs > 1e9 {
+            return Duration::try_from_secs_f64(secs).ok()?.checked_sub(now);
+        }
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+        return (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .ok();
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let unit_start = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number = rest[..unit_start].parse::<f64>().ok()?;
+        let unit_len = rest[unit_start..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - unit_start);
+        let secs = match &rest[unit_start..unit_start + unit_len] {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+        total += Duration::try_from_secs_f64(secs).ok()?;
+        rest = &rest[unit_start + unit_len..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parse_retry_after;
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_retry_after("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_retry_after("20ms"), Some(Duration::from_millis(20)));
+        // A date in the past asks for no delay at all
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
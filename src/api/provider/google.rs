@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use anyhow::{bail, Context};
+use anyhow::Context;
 use axum::{
     body::Body,
     http::{HeaderMap, HeaderValue},
@@ -18,9 +18,11 @@ use crate::{
     api::uni_ollama::message::{
         OllamaChatRequest, OllamaChatResponse, RespMessage, Role,
     },
-    common::gemini_stream::get_ollama_stream,
+    common::{gemini_stream::get_ollama_stream, tokenizer::UsageEstimator},
 };
 
+use super::{error::UpstreamError, message::Usage};
+
 #[derive(Debug, Serialize)]
 pub(crate) struct GeminiRequest {
     pub contents: Vec<Content>,
@@ -43,6 +45,7 @@ pub(crate) struct Part {
 #[serde(rename_all = "camelCase")]
 pub(crate) struct GeminiResponse {
     pub candidates: Vec<Candidate>,
+    #[serde(default)]
     pub usage_metadata: UsageMetadata,
     #[allow(unused)]
     pub model_version: String,
@@ -74,13 +77,58 @@ pub(crate) struct UsageMetadata {
     pub total_token_count: usize,
 }
 
-pub(crate) async fn chat_completion(
-    chat_req: OllamaChatRequest,
-    model_id: String,
-    model_name: String,
-    api_key: String,
-    client: Client,
-) -> anyhow::Result<Response> {
+impl UsageMetadata {
+    /// Replace the usage with a local estimate when Gemini does not report it
+    pub(crate) fn or_estimate(
+        self,
+        estimator: &UsageEstimator,
+        completion: &str,
+    ) -> Self {
+        if self.total_token_count > 0 {
+            return self;
+        }
+        let usage = estimator.estimate(completion);
+        Self {
+            prompt_token_count: usage.prompt_tokens as usize,
+            total_token_count: usage.total_tokens as usize,
+        }
+    }
+
+    /// The completion is whatever is not the prompt, including the thoughts
+    pub(crate) fn usage(&self) -> Usage {
+        let prompt_tokens = self.prompt_token_count as u32;
+        let total_tokens = self.total_token_count as u32;
+        Usage {
+            completion_tokens: total_tokens.saturating_sub(prompt_tokens),
+            prompt_tokens,
+            total_tokens,
+        }
+    }
+}
+
+/// The endpoint of the Gemini api at `base_url` generating with `model_name`
+pub(crate) fn request_url(
+    base_url: &str,
+    model_name: &str,
+    api_key: &str,
+    stream: bool,
+) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    if stream {
+        format!(
+            "{base_url}/models/{model_name}:streamGenerateContent?alt=sse&key={api_key}"
+        )
+    } else {
+        format!("{base_url}/models/{model_name}:generateContent?key={api_key}")
+    }
+}
+
+/// Send `chat_req` to `url`, see [`request_url`]
+pub(crate) async fn send_request(
+    url: String,
+    chat_req: &OllamaChatRequest,
+    client: &Client,
+) -> anyhow::Result<reqwest::Response> {
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
     // Add Accept header to receive stream response
@@ -88,43 +136,32 @@ pub(crate) async fn chat_completion(
         headers.insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
     }
 
-    // gen url
-    let url = if chat_req.stream {
-        format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:streamGenerateContent?alt=sse&key={}",
-        model_name, api_key
-    )
-    } else {
-        format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-        model_name, api_key
-    )
-    };
     let (contents, system_instruction) = {
         let mut contents = Vec::new();
         let mut system_instruction: Option<Content> = None;
-        for msg in chat_req.messages.into_iter() {
+        for msg in chat_req.messages.iter() {
+            let text = msg.content.clone();
             if let Role::System = msg.role {
                 match system_instruction.as_mut() {
                     Some(instruct) => {
-                        instruct.parts.push(Part { text: msg.content });
+                        instruct.parts.push(Part { text });
                     }
                     None => {
                         system_instruction = Some(Content {
                             role: None,
-                            parts: vec![Part { text: msg.content }],
+                            parts: vec![Part { text }],
                         });
                     }
                 }
             } else if let Role::Assistant = msg.role {
                 contents.push(Content {
                     role: Some("model".to_string()),
-                    parts: vec![Part { text: msg.content }],
+                    parts: vec![Part { text }],
                 });
             } else {
                 contents.push(Content {
                     role: Some("user".to_string()),
-                    parts: vec![Part { text: msg.content }],
+                    parts: vec![Part { text }],
                 });
             }
         }
@@ -144,20 +181,27 @@ pub(crate) async fn chat_completion(
         .headers(headers)
         .json(&req)
         .send()
-        .await?;
+        .await
+        .map_err(UpstreamError::Request)?;
 
     // Check response status
     if !api_resp.status().is_success() {
-        let error_text = api_resp.text().await?;
-        tracing::error!("Failed to request API: {}", error_text);
-        bail!("error:{error_text}")
+        return Err(UpstreamError::from_response(api_resp).await.into());
     }
+    Ok(api_resp)
+}
 
-    // Process api response
-    if chat_req.stream {
-        process_streaming(model_id, api_resp).await
+/// Convert the response of the Gemini api into ollama format
+pub(crate) async fn process_response(
+    model_id: String,
+    stream: bool,
+    api_resp: reqwest::Response,
+    estimator: UsageEstimator,
+) -> anyhow::Result<Response> {
+    if stream {
+        process_streaming(model_id, api_resp, estimator).await
     } else {
-        process_non_streaming(model_id, api_resp).await
+        process_non_streaming(model_id, api_resp, estimator).await
     }
 }
 
@@ -174,27 +218,23 @@ pub(crate) fn gen_last_ollama_message(
     let mut resp = OllamaChatResponse::default();
 
     resp.fill_option();
-    let UsageMetadata {
-        prompt_token_count,
-        total_token_count,
-    } = usage;
+    resp.add_usage(&usage.usage());
     resp.model = model_id.to_string();
     resp.done = true;
-    resp.eval_count = Some(total_token_count as u32);
-    resp.prompt_eval_count = Some(prompt_token_count as u32);
     resp.eval_duration = Some(done_dur);
 
     serde_json::to_string(&resp).expect("gen ollama response nerver fails")
 }
 
-#[instrument(skip(api_resp))]
+#[instrument(skip(api_resp, estimator))]
 async fn process_streaming(
     model_id: String,
     api_resp: reqwest::Response,
+    estimator: UsageEstimator,
 ) -> anyhow::Result<Response> {
     let stream = api_resp.bytes_stream();
 
-    let ollama_resp_stream = get_ollama_stream(model_id, stream);
+    let ollama_resp_stream = get_ollama_stream(model_id, stream, estimator);
 
     let mut response_builder = Response::builder().status(200);
     let mut header = HeaderMap::new();
@@ -209,10 +249,11 @@ async fn process_streaming(
     Ok(res)
 }
 
-#[instrument(skip(api_resp))]
+#[instrument(skip(api_resp, estimator))]
 async fn process_non_streaming(
     model_id: String,
     api_resp: reqwest::Response,
+    estimator: UsageEstimator,
 ) -> anyhow::Result<Response> {
     let api_resp = api_resp
         .json::<GeminiResponse>()
@@ -231,14 +272,10 @@ async fn process_non_streaming(
     let mut resp = OllamaChatResponse::default();
 
     resp.fill_option();
-    let UsageMetadata {
-        prompt_token_count,
-        total_token_count,
-    } = api_resp.usage_metadata;
+    let usage = api_resp.usage_metadata.or_estimate(&estimator, &content);
+    resp.add_usage(&usage.usage());
     resp.model = model_id.to_string();
     resp.done = true;
-    resp.eval_count = Some(total_token_count as u32);
-    resp.prompt_eval_count = Some(prompt_token_count as u32);
     resp.message = RespMessage {
         role: Role::Assistant,
         content,
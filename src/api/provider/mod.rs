@@ -1,8 +1,5 @@
-pub(crate) mod aliyun;
-pub(crate) mod bytedance;
 pub(crate) mod common;
-pub(crate) mod deepseek;
+pub(crate) mod error;
 pub(crate) mod google;
 pub(crate) mod message;
-pub(crate) mod siliconflow;
-pub(crate) mod tencent;
+pub(crate) mod retry;
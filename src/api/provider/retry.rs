@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::{
+    api::uni_ollama::config::{RetryPolicy, RetryableError},
+    common::random_u64,
+};
+
+use super::error::{TimeoutKind, UpstreamError};
+
+impl RetryPolicy {
+    /// The delay before retrying a request that failed with `err` after `attempt` attempts,
+    /// `None` if it should not be retried
+    pub(crate) fn backoff(&self, attempt: u32, err: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let retry_after = match UpstreamError::of(err)? {
+            UpstreamError::Request(e) => {
+                let kind = if e.is_connect() {
+                    RetryableError::Connect
+                } else if e.is_timeout() {
+                    RetryableError::Timeout
+                } else {
+                    return None;
+                };
+                if !self.retryable_errors.contains(&kind) {
+                    return None;
+                }
+                None
+            }
+            UpstreamError::Status {
+                status,
+                retry_after,
+                ..
+            } => {
+                if !self.retryable_status.contains(&status.as_u16()) {
+                    return None;
+                }
+                *retry_after
+            }
+            // Waiting for a key to cool down or a free slot is left to the next target
+            UpstreamError::Unavailable(_)
+            | UpstreamError::QueueFull(_)
+            | UpstreamError::Timeout(TimeoutKind::Queue, _) => return None,
+            UpstreamError::Timeout(..) => {
+                if !self.retryable_errors.contains(&RetryableError::Timeout) {
+                    return None;
+                }
+                None
+            }
+        };
+        let max_backoff = Duration::from_millis(self.max_backoff_ms);
+        // Waiting that long is left to the next target
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= max_backoff).then_some(retry_after);
+        }
+        let backoff = Duration::from_millis(self.base_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(max_backoff);
+        Some(jittered(backoff, self.jitter))
+    }
+}
+
+/// Spread `delay` randomly by up to `jitter` of it, so clients don't retry in lockstep
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    let random = random_u64() as f64 / u64::MAX as f64;
+    delay.mul_f64(1.0 + jitter.clamp(0.0, 1.0) * (2.0 * random - 1.0))
+}
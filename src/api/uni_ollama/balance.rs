@@ -0,0 +1,366 @@
+//! Spread requests across api_keys and model targets, see [`BalanceStrategy`]
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{body::Body, http::HeaderMap, response::Response};
+use futures::StreamExt;
+use parking_lot::Mutex;
+
+use crate::common::random_u64;
+
+use super::config::{ApiKeyInfo, BalanceStrategy, ModelTarget, SelectedApiKeyInfo};
+
+/// The header identifying a client for [`BalanceStrategy::Sticky`]
+pub(crate) const CLIENT_ID_HEADER: &str = "x-uni-llm-client-id";
+/// The weight of the latest sample in the moving average of the time to the first token
+const EWMA_ALPHA: f64 = 0.3;
+
+/// What the statistics are collected for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum StatsKey {
+    /// An api_key by its api_key_id and its value in [`ApiKeyInfo::api_key`],
+    /// so that the statistics stay with the key when the keys are reordered
+    Key(String, String),
+    /// A target of a model
+    Target(ModelTarget),
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    in_flight: usize,
+    /// The moving average of the time to the first token in seconds
+    ewma_ttft: Option<f64>,
+}
+
+/// The statistics the strategies rely on, shared by all requests
+#[derive(Default, Clone)]
+pub(crate) struct Balancer {
+    stats: Arc<Mutex<HashMap<StatsKey, Stats>>>,
+    /// The round-robin counters of the models
+    turns: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Identify the client of a request for [`BalanceStrategy::Sticky`],
+/// by the [`CLIENT_ID_HEADER`] or else the address of the client
+pub(crate) fn client_hash(headers: &HeaderMap, addr: SocketAddr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match headers.get(CLIENT_ID_HEADER) {
+        Some(id) => id.as_bytes().hash(&mut hasher),
+        None => addr.ip().hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+fn weight_of(weights: &[u32], index: usize) -> u32 {
+    weights.get(index).copied().unwrap_or(1)
+}
+
+/// Pick one of `positions` randomly in proportion to the weights of `candidates`
+fn weighted_random(candidates: &[(StatsKey, u32)], positions: &[usize]) -> usize {
+    let total = positions
+        .iter()
+        .map(|i| candidates[*i].1 as u64)
+        .sum::<u64>();
+    if total == 0 {
+        return positions[0];
+    }
+    let mut point = random_u64() % total;
+    for &i in positions {
+        let weight = candidates[i].1 as u64;
+        if point < weight {
+            return i;
+        }
+        point -= weight;
+    }
+    positions[positions.len() - 1]
+}
+
+impl Balancer {
+    /// Pick one of `candidates`, which come with their weights. Returns its position
+    fn pick(
+        &self,
+        group: &str,
+        strategy: BalanceStrategy,
+        candidates: &[(StatsKey, u32)],
+        client: Option<u64>,
+    ) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let all = (0..candidates.len()).collect::<Vec<_>>();
+        let scores = {
+            let stats = self.stats.lock();
+            let score = |key: &StatsKey, weight: u32| {
+                let stats = stats.get(key);
+                match strategy {
+                    BalanceStrategy::LeastInFlight => {
+                        stats.map_or(0, |s| s.in_flight) as f64 / weight.max(1) as f64
+                    }
+                    // Untried ones go first, so every one gets measured,
+                    // failed ones have the penalty of [`Balancer::record_failure`]
+                    BalanceStrategy::LowestLatency => {
+                        stats.and_then(|s| s.ewma_ttft).unwrap_or(0.0)
+                    }
+                    _ => 0.0,
+                }
+            };
+            candidates
+                .iter()
+                .map(|(key, weight)| score(key, *weight))
+                .collect::<Vec<_>>()
+        };
+        let picked = match (strategy, client) {
+            (BalanceStrategy::RoundRobin, _) => {
+                let mut turns = self.turns.lock();
+                let turn = turns.entry(group.to_string()).or_default();
+                *turn = turn.wrapping_add(1);
+                (*turn - 1) % candidates.len()
+            }
+            (BalanceStrategy::Weighted, _) | (BalanceStrategy::Sticky, None) => {
+                weighted_random(candidates, &all)
+            }
+            (BalanceStrategy::LeastInFlight | BalanceStrategy::LowestLatency, _) => {
+                let min = scores.iter().copied().fold(f64::INFINITY, f64::min);
+                let ties = all
+                    .into_iter()
+                    .filter(|i| scores[*i] <= min)
+                    .collect::<Vec<_>>();
+                weighted_random(candidates, &ties)
+            }
+            // Rendezvous hashing, a client only moves when its pick goes away
+            (BalanceStrategy::Sticky, Some(client)) => {
+                let rank = |(key, weight): &(StatsKey, u32)| {
+                    let mut hasher = DefaultHasher::new();
+                    client.hash(&mut hasher);
+                    key.hash(&mut hasher);
+                    let point = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                    *weight as f64 / -point.ln()
+                };
+                all.into_iter()
+                    .max_by(|a, b| {
+                        rank(&candidates[*a]).total_cmp(&rank(&candidates[*b]))
+                    })
+                    .expect("candidates is not empty")
+            }
+        };
+        Some(picked)
+    }
+
+    /// Move the target picked with `strategy` to the front of `targets`,
+    /// the others keep their order to fail over to
+    pub(crate) fn order_targets(
+        &self,
+        model_id: &str,
+        strategy: BalanceStrategy,
+        weights: &[u32],
+        targets: &mut [ModelTarget],
+        client: Option<u64>,
+    ) {
+        let candidates = targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| (StatsKey::Target(target.clone()), weight_of(weights, i)))
+            .collect::<Vec<_>>();
+        if let Some(picked) = self.pick(model_id, strategy, &candidates, client) {
+            targets[..=picked].rotate_right(1);
+        }
+    }
+
+    /// Add a sample of the time to the first token of `keys` to their moving averages
+    fn record(&self, keys: &[StatsKey], sample: Duration) {
+        let sample = sample.as_secs_f64();
+        let mut stats = self.stats.lock();
+        for key in keys {
+            let stats = stats.entry(key.clone()).or_default();
+            stats.ewma_ttft = Some(match stats.ewma_ttft {
+                Some(ewma) => EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * ewma,
+                None => sample,
+            });
+        }
+    }
+
+    /// Take a request for `keys` that failed before its first token as one
+    /// that took `penalty`, the first token timeout, so they stop winning
+    /// [`BalanceStrategy::LowestLatency`] until they recover
+    pub(crate) fn record_failure(&self, keys: &[StatsKey], penalty: Duration) {
+        self.record(keys, penalty);
+    }
+
+    /// Count a request made for `keys` as in flight, until the returned guard is dropped
+    pub(crate) fn start(&self, keys: Vec<StatsKey>) -> InFlight {
+        let mut stats = self.stats.lock();
+        for key in &keys {
+            stats.entry(key.clone()).or_default().in_flight += 1;
+        }
+        InFlight {
+            balancer: self.clone(),
+            keys,
+            started: Instant::now(),
+            responded: false,
+        }
+    }
+}
+
+/// A request in flight, see [`Balancer::start`]
+pub(crate) struct InFlight {
+    balancer: Balancer,
+    keys: Vec<StatsKey>,
+    started: Instant,
+    responded: bool,
+}
+
+impl InFlight {
+    fn record_first_token(&mut self) {
+        if self.responded {
+            return;
+        }
+        self.responded = true;
+        self.balancer.record(&self.keys, self.started.elapsed());
+    }
+
+    /// Keep the request in flight until the body of `res` is consumed,
+    /// and take its first chunk as the first token
+    pub(crate) fn attach(mut self, res: Response) -> Response {
+        res.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                self.record_first_token();
+                chunk
+            }))
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut stats = self.balancer.stats.lock();
+        for key in &self.keys {
+            if let Some(stats) = stats.get_mut(key) {
+                stats.in_flight = stats.in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl ApiKeyInfo {
+    /// Pick one of the available keys `has_room` accepts with [`Self::balance`].
+    /// Returns `None` if no key is available
+    pub(crate) fn select(
+        &mut self,
+        api_key_id: &str,
+        balancer: &Balancer,
+        client: Option<u64>,
+        has_room: &dyn Fn(&str) -> bool,
+    ) -> Option<SelectedApiKeyInfo> {
+        let strategy = self.balance.unwrap_or_default();
+        if strategy == BalanceStrategy::RoundRobin {
+            return self.selected_where(has_room);
+        }
+        self.health
+            .resize_with(self.api_key.len(), Default::default);
+        let now = Instant::now();
+        let indexes = (0..self.api_key.len())
+            .filter(|i| self.is_usable(*i, now) && has_room(&self.api_key[*i]))
+            .collect::<Vec<_>>();
+        let candidates = indexes
+            .iter()
+            .map(|i| {
+                let key = StatsKey::Key(api_key_id.to_string(), self.api_key[*i].clone());
+                (key, weight_of(&self.weights, *i))
+            })
+            .collect::<Vec<_>>();
+        let picked = balancer.pick(api_key_id, strategy, &candidates, client)?;
+        Some(self.selected_at(indexes[picked]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::api::uni_ollama::config::{ApiKeyInfo, BalanceStrategy};
+
+    use super::{Balancer, StatsKey};
+
+    fn keys() -> ApiKeyInfo {
+        ApiKeyInfo {
+            api_key: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_balance_keys() {
+        let balancer = Balancer::default();
+        let mut info = keys();
+        info.balance = Some(BalanceStrategy::LeastInFlight);
+        let busy = ["a", "b"].map(|key| {
+            balancer.start(vec![StatsKey::Key("k".to_string(), key.to_string())])
+        });
+        assert_eq!(
+            info.select("k", &balancer, None, &|_| true)
+                .unwrap()
+                .api_key,
+            "c"
+        );
+        drop(busy);
+
+        // A zero weight is never picked at random
+        info.balance = Some(BalanceStrategy::Weighted);
+        info.weights = vec![0, 0, 1];
+        for _ in 0..10 {
+            assert_eq!(
+                info.select("k", &balancer, None, &|_| true)
+                    .unwrap()
+                    .api_key,
+                "c"
+            );
+        }
+
+        // The same client keeps its key
+        info.balance = Some(BalanceStrategy::Sticky);
+        info.weights.clear();
+        let first = info
+            .select("k", &balancer, Some(42), &|_| true)
+            .unwrap()
+            .index;
+        for _ in 0..10 {
+            assert_eq!(
+                info.select("k", &balancer, Some(42), &|_| true)
+                    .unwrap()
+                    .index,
+                first
+            );
+        }
+    }
+
+    #[test]
+    fn test_lowest_latency() {
+        let balancer = Balancer::default();
+        let mut info = keys();
+        info.balance = Some(BalanceStrategy::LowestLatency);
+        let key = |key: &str| vec![StatsKey::Key("k".to_string(), key.to_string())];
+        balancer.record(&key("b"), Duration::from_millis(200));
+        balancer.record(&key("c"), Duration::from_millis(500));
+        // The untried key goes first, until it fails before its first token
+        assert_eq!(
+            info.select("k", &balancer, None, &|_| true)
+                .unwrap()
+                .api_key,
+            "a"
+        );
+        balancer.record_failure(&key("a"), Duration::from_secs(30));
+        for _ in 0..10 {
+            assert_eq!(
+                info.select("k", &balancer, None, &|_| true)
+                    .unwrap()
+                    .api_key,
+                "b"
+            );
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Exact-match cache of complete answers, see [`ModelInfo::cache`]
+//!
+//! [`ModelInfo::cache`]: crate::ModelInfo::cache
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, HeaderValue},
+    response::Response,
+};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::api::provider::message::Usage;
+
+use super::{
+    config::{CacheConfig, ModelInfo},
+    continuation::{Lines, THINK_END, THINK_START},
+    message::{
+        gen_last_message, gen_ollama_message, gen_ollama_think_end_message,
+        gen_ollama_think_start_message, OllamaChatRequest, RespMessage, Role,
+    },
+};
+
+/// `hit` or `miss` on the responses of cacheable requests
+pub(crate) const CACHE_HEADER: &str = "x-uni-llm-cache";
+
+/// The key of a cacheable request, which is everything that affects the answer,
+/// including the targets the model resolves to, so that the answers of a replaced
+/// target are not reused. `None` unless the model caches answers and the request is
+/// deterministic or the model caches any request
+pub(crate) fn cache_key(
+    payload: &OllamaChatRequest,
+    model_info: &ModelInfo,
+) -> Option<String> {
+    let config = model_info.cache.as_ref()?;
+    let temperature = payload
+        .options
+        .as_ref()
+        .and_then(|options| options.get("temperature"))
+        .and_then(Value::as_f64);
+    if !config.any_temperature && temperature != Some(0.0) {
+        return None;
+    }
+    // The fields of a `Value` are sorted, unlike those of a `HashMap`
+    let key = json!({
+        "model": payload.model,
+        "targets": model_info.targets(),
+        "messages": payload.messages,
+        "tools": payload.tools,
+        "format": payload.format,
+        "options": payload.options,
+    });
+    Some(key.to_string())
+}
+
+/// A complete answer, replayed in whichever form the request asks for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CachedAnswer {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reasoning: Option<String>,
+    content: String,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+}
+
+impl CachedAnswer {
+    /// Split the `<think>` block off `full`, the contents of a response put together
+    fn new(full: &str, prompt_tokens: u32, completion_tokens: u32) -> Self {
+        let trim = |s: &str| s.strip_prefix('\n').unwrap_or(s).to_string();
+        let (reasoning, content) = match full
+            .strip_prefix(THINK_START)
+            .and_then(|rest| rest.split_once(THINK_END))
+        {
+            Some((reasoning, content)) => (Some(trim(reasoning)), trim(content)),
+            None => (None, full.to_string()),
+        };
+        Self {
+            reasoning,
+            content,
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    /// The answer in the same format as a response of [`super::chat::dispatch`]
+    pub(crate) fn response(&self, model_id: &str, stream: bool) -> Response {
+        let usage = Usage {
+            completion_tokens: self.completion_tokens,
+            prompt_tokens: self.prompt_tokens,
+            total_tokens: self.prompt_tokens + self.completion_tokens,
+        };
+        let message = |content: String| RespMessage {
+            role: Role::Assistant,
+            content,
+            images: None,
+        };
+        let (body, content_type) = if stream {
+            let mut lines = Vec::new();
+            if let Some(reasoning) = &self.reasoning {
+                lines.push(gen_ollama_think_start_message(model_id));
+                lines.push(gen_ollama_message(model_id, message(reasoning.clone())));
+                lines.push(gen_ollama_think_end_message(model_id));
+            }
+            lines.push(gen_ollama_message(model_id, message(self.content.clone())));
+            lines.push(gen_last_message(model_id, None, &usage, 0));
+            let body = lines.join("\n") + "\n";
+            (body, "application/x-ndjson")
+        } else {
+            let mut content = String::new();
+            if let Some(reasoning) = &self.reasoning {
+                content.push_str("<think>\n");
+                content.push_str(reasoning);
+                content.push_str("</think>\n");
+            }
+            content.push_str(&self.content);
+            let body = gen_last_message(model_id, Some(message(content)), &usage, 0);
+            (body, "application/json")
+        };
+        let mut res = Response::new(Body::from(body));
+        let headers = res.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+        res
+    }
+}
+
+/// A line of an ollama response, see [`super::message::OllamaChatResponse`]
+#[derive(Deserialize)]
+struct AnswerLine {
+    #[serde(default)]
+    message: Option<RespMessage>,
+    #[serde(default)]
+    done: bool,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+}
+
+/// Puts the answer of a response together as it streams by
+#[derive(Default)]
+struct Recorder {
+    lines: Lines,
+    content: String,
+    /// The usage of the last line, once it arrived
+    usage: Option<(u32, u32)>,
+}
+
+impl Recorder {
+    fn feed(&mut self, chunk: &[u8]) {
+        for line in self.lines.feed(chunk) {
+            let Ok(line) = serde_json::from_slice::<AnswerLine>(&line) else {
+                continue;
+            };
+            if let Some(message) = line.message {
+                self.content.push_str(&message.content);
+            }
+            if line.done {
+                self.usage = Some((
+                    line.prompt_eval_count.unwrap_or_default(),
+                    line.eval_count.unwrap_or_default(),
+                ));
+            }
+        }
+    }
+
+    /// The answer, unless the response ended before it was done
+    fn finish(mut self) -> Option<CachedAnswer> {
+        // A response without streaming has no trailing newline
+        self.feed(b"\n");
+        let (prompt_tokens, completion_tokens) = self.usage?;
+        Some(CachedAnswer::new(
+            &self.content,
+            prompt_tokens,
+            completion_tokens,
+        ))
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    /// Unix time in seconds
+    stored_at: u64,
+    used: u64,
+}
+
+/// Values kept in memory, evicting the least recently used one when full
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, Entry<V>>,
+    /// The keys by when they were last used
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// The value of `key` unless it was stored `ttl` seconds or more before `now`
+    pub(crate) fn get<Q>(&mut self, key: &Q, now: u64, ttl: u64) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.entries.get_mut(key)?;
+        let key = self
+            .order
+            .remove(&entry.used)
+            .expect("every entry is ordered");
+        if entry.stored_at + ttl <= now {
+            self.entries.remove::<K>(&key);
+            return None;
+        }
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(entry.value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V, stored_at: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let entry = Entry {
+            value,
+            stored_at,
+            used: self.tick,
+        };
+        if let Some(old) = self.entries.insert(key.clone(), entry) {
+            self.order.remove(&old.used);
+        }
+        self.order.insert(self.tick, key);
+        self.evict();
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// An answer stored in [`CacheConfig::dir`]
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    /// Tells apart the keys sharing a file name
+    key: String,
+    /// Unix time in seconds, the TTL of the model counts from here
+    stored_at: u64,
+    answer: CachedAnswer,
+}
+
+/// The cached answers of all models
+#[derive(Clone)]
+pub(crate) struct ResponseCache {
+    memory: Arc<Mutex<Lru<String, CachedAnswer>>>,
+    dir: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: Option<&CacheConfig>) -> Self {
+        let config = config.cloned().unwrap_or_default();
+        Self {
+            memory: Arc::new(Mutex::new(Lru::new(config.max_entries))),
+            dir: Arc::new(Mutex::new(config.dir)),
+        }
+    }
+
+    /// Apply a reloaded config, the answers in memory are kept up to the new capacity
+    pub(crate) fn reconfigure(&self, config: Option<&CacheConfig>) {
+        let config = config.cloned().unwrap_or_default();
+        self.memory.lock().resize(config.max_entries);
+        *self.dir.lock() = config.dir;
+    }
+
+    /// The file of `key` in [`CacheConfig::dir`]
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        // FNV-1a, which unlike the std hasher is the same across builds
+        let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        Some(self.dir.lock().as_ref()?.join(format!("{hash:016x}.json")))
+    }
+
+    /// The answer to `key` unless it was stored `ttl` or longer ago
+    pub(crate) async fn get(&self, key: &str, ttl: Duration) -> Option<CachedAnswer> {
+        let now = unix_now();
+        let ttl = ttl.as_secs();
+        if let Some(answer) = self.memory.lock().get(key, now, ttl) {
+            return Some(answer);
+        }
+        let path = self.path(key)?;
+        let entry = tokio::fs::read(&path).await.ok()?;
+        // Written by an older version, or expired
+        let entry = serde_json::from_slice::<DiskEntry>(&entry)
+            .ok()
+            .filter(|entry| entry.stored_at + ttl > now);
+        let Some(entry) = entry else {
+            let _ = tokio::fs::remove_file(&path).await;
+            return None;
+        };
+        if entry.key != key {
+            return None;
+        }
+        self.memory
+            .lock()
+            .insert(entry.key, entry.answer.clone(), entry.stored_at);
+        Some(entry.answer)
+    }
+
+    fn insert(&self, key: String, answer: CachedAnswer) {
+        let stored_at = unix_now();
+        if let Some(path) = self.path(&key) {
+            let entry = DiskEntry {
+                key: key.clone(),
+                stored_at,
+                answer: answer.clone(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = write_entry(&path, &entry).await {
+                    tracing::warn!("Failed to store the cached answer {path:?}: {e}");
+                }
+            });
+        }
+        self.memory.lock().insert(key, answer, stored_at);
+    }
+
+    /// Cache the answer of `res` under `key` once it is complete
+    pub(crate) fn capture(&self, key: String, mut res: Response) -> Response {
+        res.headers_mut()
+            .insert(CACHE_HEADER, HeaderValue::from_static("miss"));
+        if !res.status().is_success() {
+            return res;
+        }
+        let cache = self.clone();
+        res.map(|body| {
+            let mut stream = body.into_data_stream();
+            Body::from_stream(async_stream::stream! {
+                let mut recorder = Recorder::default();
+                while let Some(chunk) = stream.next().await {
+                    match &chunk {
+                        Ok(chunk) => recorder.feed(chunk),
+                        Err(_) => {
+                            yield chunk;
+                            return;
+                        }
+                    }
+                    yield chunk;
+                }
+                if let Some(answer) = recorder.finish() {
+                    cache.insert(key, answer);
+                }
+            })
+        })
+    }
+}
+
+/// Write `entry` to `path` all at once, so that a reader never sees half of it
+async fn write_entry(path: &std::path::Path, entry: &DiskEntry) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, serde_json::to_vec(entry)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::provider::message::Usage;
+    use crate::api::uni_ollama::message::{
+        gen_last_message, gen_ollama_message, gen_ollama_think_end_message,
+        gen_ollama_think_start_message, RespMessage, Role,
+    };
+
+    use std::time::Duration;
+
+    use crate::{
+        api::{mock, uni_ollama::config::CacheConfig},
+        ModelCacheConfig, ModelInfo,
+    };
+
+    use super::{
+        cache_key, unix_now, write_entry, CachedAnswer, DiskEntry, Lru, Recorder,
+        ResponseCache,
+    };
+
+    fn message(content: &str) -> RespMessage {
+        RespMessage {
+            role: Role::Assistant,
+            content: content.to_string(),
+            images: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_answer() {
+        let usage = Usage {
+            completion_tokens: 5,
+            prompt_tokens: 7,
+            total_tokens: 12,
+        };
+        let stream = [
+            gen_ollama_think_start_message("m"),
+            gen_ollama_message("m", message("hmm")),
+            gen_ollama_think_end_message("m"),
+            gen_ollama_message("m", message("Hello")),
+            gen_ollama_message("m", message(" world")),
+            gen_last_message("m", None, &usage, 3),
+        ]
+        .map(|line| format!("{line}\n"))
+        .concat();
+        let mut recorder = Recorder::default();
+        // Split in the middle of a line
+        let (head, tail) = stream.as_bytes().split_at(10);
+        recorder.feed(head);
+        recorder.feed(tail);
+        let answer = recorder.finish().unwrap();
+        assert_eq!(
+            answer,
+            CachedAnswer::new("<think>hmm</think>Hello world", 7, 5)
+        );
+
+        // Replayed in both forms, the answer is recorded the same again
+        for stream in [true, false] {
+            let res = answer.response("m", stream);
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let mut recorder = Recorder::default();
+            recorder.feed(&body);
+            assert_eq!(recorder.finish().as_ref(), Some(&answer));
+        }
+
+        // An answer cut short is not cached
+        let mut recorder = Recorder::default();
+        recorder.feed(gen_ollama_message("m", message("Hel")).as_bytes());
+        assert!(recorder.finish().is_none());
+    }
+
+    #[test]
+    fn test_lru() {
+        let answer = CachedAnswer::new("a", 1, 1);
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_string(), answer.clone(), 0);
+        lru.insert("b".to_string(), answer.clone(), 0);
+        assert!(lru.get("a", 0, 100).is_some());
+        // `b` is the least recently used
+        lru.insert("c".to_string(), answer, 0);
+        assert!(lru.get("b", 0, 100).is_none());
+        assert!(lru.get("a", 0, 100).is_some());
+        assert!(lru.get("c", 100, 100).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_disk_entries() {
+        let mut model_info = ModelInfo {
+            cache: Some(ModelCacheConfig {
+                ttl_secs: 60,
+                any_temperature: true,
+            }),
+            ..mock::model("m", "a")
+        };
+        let key = cache_key(&mock::request("m", false), &model_info).unwrap();
+        // The answers of another target are not reused
+        model_info.api_key_id = "b".to_string();
+        assert_ne!(
+            cache_key(&mock::request("m", false), &model_info),
+            Some(key.clone())
+        );
+
+        let dir = std::env::temp_dir().join("uni-llm-test-cache");
+        let config = CacheConfig {
+            max_entries: 8,
+            dir: Some(dir),
+        };
+        let path = ResponseCache::new(Some(&config)).path(&key).unwrap();
+        let entry = DiskEntry {
+            key: key.clone(),
+            stored_at: unix_now() - 120,
+            answer: CachedAnswer::new("a", 1, 1),
+        };
+        // The TTL counts from when it was stored, not from when it is loaded
+        write_entry(&path, &entry).await.unwrap();
+        let ttl = Duration::from_secs(3600);
+        assert!(ResponseCache::new(Some(&config))
+            .get(&key, ttl)
+            .await
+            .is_some());
+        let ttl = Duration::from_secs(60);
+        assert!(ResponseCache::new(Some(&config))
+            .get(&key, ttl)
+            .await
+            .is_none());
+        assert!(!path.exists());
+    }
+}
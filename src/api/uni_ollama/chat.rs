@@ -1,12 +1,39 @@
-use anyhow::Context;
-use axum::{extract::State, response::Response};
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::time::Instant;
+
+use anyhow::{bail, Context};
+
+use serde_json::Value;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{Extensions, HeaderMap, HeaderValue},
+    response::Response,
+};
 
 use crate::{
     api::{
-        self,
-        provider::{self, aliyun, bytedance, deepseek, google, siliconflow, tencent},
-        uni_ollama::message::OllamaChatRequest,
+        provider::{self, error::UpstreamError, google},
+        uni_ollama::{
+            balance::{client_hash, StatsKey},
+            cache::cache_key,
+            config::{
+                ApiKeyProvider, ModelInfo, ModelTarget, SelectedApiKeyInfo, TlsConfig,
+                TokenizerKind,
+            },
+            continuation::continue_on_break,
+            hedge::hedge,
+            history::{
+                fit_history, PromptTokens, DROPPED_MESSAGES_HEADER,
+                SUMMARIZED_MESSAGES_HEADER,
+            },
+            message::OllamaChatRequest,
+            normalize::{normalize_messages, strip_history_reasoning},
+            timeout::{classify_connect_timeout, first_token},
+        },
     },
+    common::tokenizer::{tokenizer_or_heuristic, UsageEstimator},
     SharedStateRef,
 };
 
@@ -16,122 +43,586 @@ use super::error::AppError;
 /// See [ollama chat api](https://github.com/ollama/ollama/blob/main/docs/api.md#generate-a-chat-completion)
 pub(crate) async fn api_chat(
     State(state): State<SharedStateRef>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    extensions: Extensions,
+    headers: HeaderMap,
     body: String,
 ) -> Result<Response, AppError> {
+    // Counted by the rate limiter, if it is enabled
+    let counted = extensions.get::<PromptTokens>().cloned();
     let payload: OllamaChatRequest =
         serde_json::from_str(&body).context("Get ChatRequest")?;
-    // Retrieve specific information about the calling model,
-    // and invoke the corresponding interface to complete the API call based on the API provider
-    let (model_id, model_name, api_info) = {
-        let (model_name, api_key_id) = {
-            let guard = state.model_config.read();
-            let model_name = guard
-                .models
-                .get(&payload.model)
-                .context("Invalid model id")?
-                .name
-                .clone();
-            let api_key_id = guard
-                .models
-                .get(&payload.model)
-                .context("Invalid model id")?
-                .api_key_id
-                .clone();
-            (model_name, api_key_id)
+    // Retrieve specific information about the calling model
+    let (model_info, tokenizer_kind) = {
+        let guard = state.model_config.read();
+        let model_info = guard
+            .models
+            .get(&payload.model)
+            .context("Invalid model id")?
+            .clone();
+        let tokenizer_kind = guard.tokenizer_kind(&model_info);
+        (model_info, tokenizer_kind)
+    };
+    let client = client_hash(&headers, addr);
+    if model_info.coalesce {
+        // Identical up to the formatting and the order of the fields
+        let key = serde_json::from_str::<Value>(&body)?.to_string();
+        let call = chat(
+            state.clone(),
+            payload,
+            model_info,
+            tokenizer_kind,
+            client,
+            counted,
+        );
+        return Ok(state.flights.run(key, call).await?);
+    }
+    Ok(chat(state, payload, model_info, tokenizer_kind, client, counted).await?)
+}
+
+/// Fit the history of `payload` into the context window of the model and dispatch it,
+/// `counted` are the tokens of its messages if they are already known
+async fn chat(
+    state: SharedStateRef,
+    mut payload: OllamaChatRequest,
+    model_info: ModelInfo,
+    tokenizer_kind: TokenizerKind,
+    client: u64,
+    counted: Option<PromptTokens>,
+) -> anyhow::Result<Response> {
+    let stripped =
+        strip_history_reasoning(&payload.model, &model_info, &mut payload.messages);
+    let counted = counted.filter(|_| !stripped);
+    let cache = cache_key(&payload, &model_info);
+    if let (Some(key), Some(config)) = (&cache, &model_info.cache) {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        if let Some(answer) = state.cache.get(key, ttl).await {
+            tracing::info!("Answer {} from the cache", payload.model);
+            return Ok(answer.response(&payload.model, payload.stream));
+        }
+    }
+    // Estimate the prompt locally, to fit it into the context window and to fill in missing usage
+    let tokenizer = tokenizer_or_heuristic(&tokenizer_kind).await;
+    let fitted = fit_history(
+        &state,
+        &payload.model,
+        &model_info,
+        tokenizer.as_ref(),
+        &mut payload.messages,
+        counted,
+    )
+    .await?;
+    let estimator = UsageEstimator::new(tokenizer, fitted.prompt_tokens as u32);
+    let mut res = dispatch(&state, payload, &model_info, estimator, Some(client)).await?;
+    if let Some(key) = cache {
+        res = state.cache.capture(key, res);
+    }
+    if let Some(dropped) = fitted.dropped {
+        res.headers_mut()
+            .insert(DROPPED_MESSAGES_HEADER, HeaderValue::from(dropped));
+    }
+    if let Some(summarized) = fitted.summarized {
+        res.headers_mut()
+            .insert(SUMMARIZED_MESSAGES_HEADER, HeaderValue::from(summarized));
+    }
+    Ok(res)
+}
+
+/// Make the API call to the targets of `model_info` in order,
+/// failing over to the next one on errors that happen before anything is streamed.
+///
+/// `client` identifies the client for [`crate::BalanceStrategy::Sticky`]
+pub(crate) async fn dispatch(
+    state: &SharedStateRef,
+    payload: OllamaChatRequest,
+    model_info: &ModelInfo,
+    estimator: UsageEstimator,
+    client: Option<u64>,
+) -> anyhow::Result<Response> {
+    // The total timeout covers every target, so it counts from here
+    let started = Instant::now();
+    let mut targets = model_info.targets();
+    if let Some(strategy) = model_info.balance {
+        state.balancer.order_targets(
+            &payload.model,
+            strategy,
+            &model_info.weights,
+            &mut targets,
+            client,
+        );
+    }
+    // Broken streams continue on the targets that were not tried yet
+    let finish = |res: Response, remaining: &[ModelTarget]| match model_info.continuation
+    {
+        Some(mode) if payload.stream && !remaining.is_empty() => continue_on_break(
+            state,
+            &payload,
+            model_info,
+            mode,
+            remaining.to_vec(),
+            estimator.clone(),
+            client,
+            started,
+            res,
+        ),
+        _ => res,
+    };
+    let mut targets = &targets[..];
+    if let (Some(delay), [first, second, others @ ..]) =
+        (model_info.hedge_after_ms, targets)
+    {
+        let delay = Duration::from_millis(delay);
+        let res = hedge(
+            state,
+            &payload,
+            model_info,
+            [first, second],
+            delay,
+            &estimator,
+            client,
+            started,
+        );
+        match res.await {
+            Ok(res) => return Ok(finish(res, others)),
+            Err(e) if !others.is_empty() && UpstreamError::is_retryable_error(&e) => {
+                tracing::warn!(
+                    "Hedged targets of {} failed, fail over to the next one: {e}",
+                    payload.model
+                );
+                targets = others;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    for (i, target) in targets.iter().enumerate() {
+        let res = dispatch_target(
+            state,
+            &payload,
+            model_info,
+            target,
+            estimator.clone(),
+            client,
+            started,
+        );
+        match res.await {
+            Ok(res) => return Ok(finish(res, &targets[i + 1..])),
+            Err(e) if i + 1 < targets.len() && UpstreamError::is_retryable_error(&e) => {
+                tracing::warn!(
+                    "Target {}/{} of {} failed, fail over to the next one: {e}",
+                    target.api_key_id,
+                    target.name,
+                    payload.model
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    bail!("No target for model {}", payload.model)
+}
+
+/// Select an api_key for `target`,
+/// and invoke the corresponding interface to complete the API call based on the API provider.
+///
+/// `started` is when [`dispatch`] started, the total timeout counts from there
+pub(super) async fn dispatch_target(
+    state: &SharedStateRef,
+    payload: &OllamaChatRequest,
+    model_info: &ModelInfo,
+    target: &ModelTarget,
+    estimator: UsageEstimator,
+    client: Option<u64>,
+    started: Instant,
+) -> anyhow::Result<Response> {
+    let model_id = payload.model.clone();
+    let model_name = target.name.as_str();
+    let (provider, endpoint, retry, concurrency, timeouts) = {
+        let guard = state.model_config.read();
+        let api_key_info = guard
+            .api_keys
+            .get(&target.api_key_id)
+            .context("Invalid api_key_id")?;
+        let timeouts = model_info
+            .timeouts
+            .clone()
+            .unwrap_or_default()
+            .or(&api_key_info.timeouts.clone().unwrap_or_default());
+        let endpoint = Endpoint {
+            url: api_key_info.url().to_string(),
+            proxy_url: guard.proxy_of(model_info, target),
+            tls: api_key_info.tls.clone(),
         };
-        let api_info = {
+        (
+            api_key_info.provider.clone(),
+            endpoint,
+            api_key_info.retry.clone(),
+            api_key_info.concurrency.clone(),
+            timeouts,
+        )
+    };
+    let mut payload = payload.clone();
+    normalize_messages(
+        &model_id,
+        &provider,
+        model_info.supports_tools,
+        &mut payload.messages,
+    );
+    let deadline = timeouts.deadline(started);
+    let mut attempt = 1;
+    let res = loop {
+        // Every attempt takes the next api_key
+        let mut select = |has_room: &dyn Fn(&str) -> bool| {
             let mut guard = state.model_config.write();
             let api_key_info = guard
                 .api_keys
-                .get_mut(&api_key_id)
+                .get_mut(&target.api_key_id)
                 .context("Invalid api_key_id")?;
-            api_key_info.selected()
-        };
-        (payload.model.clone(), model_name, api_info)
-    };
-    // Provide the correct client instance based on whether a proxy is needed
-    let client = if api_info.need_proxy {
-        tracing::info!("start proxy: model_id:{model_id} model_name:{model_name}");
-        state
-            .proxy_client
-            .clone()
-            .with_context(|| "You've chosen to use a proxy but haven't set it up yet")?
-    } else {
-        state.client.clone()
-    };
-    // Make a request to the corresponding cloud provider's API
-    let res = match api_info.provider {
-        api::uni_ollama::config::ApiKeyProvider::Aliyun => {
-            aliyun::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
+            if !api_key_info.any_available() {
+                return Err(UpstreamError::Unavailable(target.api_key_id.clone()).into());
+            }
+            Ok(
+                api_key_info.select(
+                    &target.api_key_id,
+                    &state.balancer,
+                    client,
+                    has_room,
+                ),
             )
-            .await?
+        };
+        let (api_info, permit) = match &concurrency {
+            Some(config) => {
+                let gate = state.gates.get(&target.api_key_id);
+                let (api_info, permit) =
+                    gate.acquire(config, client, &mut select).await?;
+                (api_info, Some(permit))
+            }
+            None => {
+                let api_info = select(&|_| true)?.ok_or_else(|| {
+                    UpstreamError::Unavailable(target.api_key_id.clone())
+                })?;
+                (api_info, None)
+            }
+        };
+        let stats_keys = vec![
+            StatsKey::Key(target.api_key_id.clone(), api_info.api_key.clone()),
+            StatsKey::Target(target.clone()),
+        ];
+        let in_flight = state.balancer.start(stats_keys.clone());
+        // Nothing is streamed to the client before the first token, so it is safe to retry
+        let (first_token_at, timeout_err) = timeouts.first_token_deadline(deadline);
+        let res = tokio::time::timeout_at(first_token_at, async {
+            let connect = timeouts.connect();
+            let api_resp =
+                send_request(state, &payload, model_name, &api_info, &endpoint, connect)
+                    .await
+                    .map_err(|e| classify_connect_timeout(e, connect))?;
+            // Convert the response into ollama format
+            let res = match provider {
+                ApiKeyProvider::Google => {
+                    google::process_response(
+                        model_id.clone(),
+                        payload.stream,
+                        api_resp,
+                        estimator.clone(),
+                    )
+                    .await
+                }
+                _ => {
+                    provider::common::process_response(
+                        model_id.clone(),
+                        payload.stream,
+                        api_resp,
+                        estimator.clone(),
+                    )
+                    .await
+                }
+            }?;
+            let res = in_flight.attach(res);
+            let res = match permit {
+                Some(permit) => permit.attach(res),
+                None => res,
+            };
+            first_token(res).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(timeout_err.into()));
+        if let Some(api_key_info) = state
+            .model_config
+            .write()
+            .api_keys
+            .get_mut(&target.api_key_id)
+        {
+            api_key_info.record(api_info.index, res.as_ref().map(|_| ()));
         }
-        api::uni_ollama::config::ApiKeyProvider::Tencent => {
-            tencent::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+        if res.is_err() {
+            state
+                .balancer
+                .record_failure(&stats_keys, timeouts.first_byte());
         }
-        api::uni_ollama::config::ApiKeyProvider::Bytedance => {
-            bytedance::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+        let err = match res {
+            Ok(res) => break res,
+            Err(e) => e,
+        };
+        match retry
+            .as_ref()
+            .and_then(|retry| retry.backoff(attempt, &err))
+        {
+            Some(delay) => {
+                tracing::warn!(
+                    "Attempt {attempt} to {}/{model_name} failed, retry in {delay:?}: {err}",
+                    target.api_key_id
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            None => return Err(err),
         }
-        api::uni_ollama::config::ApiKeyProvider::DeepSeek => {
-            deepseek::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+    };
+    Ok(timeouts.limit_stream(res, deadline))
+}
+
+/// Where and how to reach the provider of a target
+struct Endpoint {
+    /// See [`crate::ApiKeyInfo::url`]
+    url: String,
+    /// See [`crate::UniModelsInfo::proxy_of`]
+    proxy_url: Option<String>,
+    tls: Option<TlsConfig>,
+}
+
+/// Make a request to the corresponding cloud provider's API
+async fn send_request(
+    state: &SharedStateRef,
+    payload: &OllamaChatRequest,
+    model_name: &str,
+    api_info: &SelectedApiKeyInfo,
+    endpoint: &Endpoint,
+    connect_timeout: Duration,
+) -> anyhow::Result<reqwest::Response> {
+    // Provide the correct client instance based on the proxy to use
+    if endpoint.proxy_url.is_some() {
+        tracing::info!(
+            "start proxy: model_id:{} model_name:{model_name}",
+            payload.model
+        );
+    }
+    let (url, tls) = match (&endpoint.tls, &api_info.provider) {
+        // The url of a built-in provider is never pinned, see `problems`
+        (Some(tls), ApiKeyProvider::Custom(_)) => {
+            let (url, tls) = tls.pin_sni(&endpoint.url)?;
+            (url, Some(tls))
         }
-        api::uni_ollama::config::ApiKeyProvider::Siliconflow => {
-            siliconflow::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+        (tls, _) => (endpoint.url.clone(), tls.clone()),
+    };
+    let client = state.clients.get(
+        endpoint.proxy_url.as_deref(),
+        tls.as_ref(),
+        connect_timeout,
+    )?;
+    let api_key = api_info.api_key.as_str();
+    let url = request_url(
+        &api_info.provider,
+        &url,
+        model_name,
+        api_key,
+        payload.stream,
+    );
+    match &api_info.provider {
+        ApiKeyProvider::Google => google::send_request(url, payload, &client).await,
+        _ => {
+            provider::common::send_request(url, payload, model_name, api_key, &client)
+                .await
         }
-        api::uni_ollama::config::ApiKeyProvider::Google => {
-            google::chat_completion(
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+    }
+}
+
+/// The endpoint of `provider` at `url`, see [`crate::ApiKeyInfo::url`]
+fn request_url(
+    provider: &ApiKeyProvider,
+    url: &str,
+    model_name: &str,
+    api_key: &str,
+    stream: bool,
+) -> String {
+    match provider {
+        ApiKeyProvider::Custom(_) => url.to_string(),
+        ApiKeyProvider::Google => google::request_url(url, model_name, api_key, stream),
+        _ => format!("{}/chat/completions", url.trim_end_matches('/')),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::StatusCode;
+
+    use crate::{
+        api::mock::{self, MockProvider},
+        ApiKeyInfo, ApiKeyProvider, ModelInfo, ModelTarget, UniModelsInfo,
+    };
+
+    use super::{dispatch, request_url};
+
+    fn target(api_key_id: &str) -> ModelTarget {
+        ModelTarget {
+            name: "m".to_string(),
+            api_key_id: api_key_id.to_string(),
         }
-        crate::ApiKeyProvider::Custom(url) => {
-            provider::common::chat_completion(
-                url,
-                payload,
-                model_id,
-                model_name,
-                api_info.api_key,
-                client,
-            )
-            .await?
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let rate_limited =
+            MockProvider::start(|_| async { mock::error(StatusCode::TOO_MANY_REQUESTS) })
+                .await;
+        let unavailable = MockProvider::start(|_| async {
+            mock::error(StatusCode::SERVICE_UNAVAILABLE)
+        })
+        .await;
+        let bad_request =
+            MockProvider::start(|_| async { mock::error(StatusCode::BAD_REQUEST) }).await;
+        let broken =
+            MockProvider::start(|_| async { mock::broken_stream(&["Hel"]) }).await;
+        let ok = MockProvider::start(|_| async { mock::stream(&["Hel", "lo"]) }).await;
+        let config = UniModelsInfo {
+            api_keys: HashMap::from([
+                (
+                    "closed".to_string(),
+                    mock::api_key(&mock::closed_url().await),
+                ),
+                ("rate_limited".to_string(), rate_limited.api_key()),
+                ("unavailable".to_string(), unavailable.api_key()),
+                ("bad_request".to_string(), bad_request.api_key()),
+                ("broken".to_string(), broken.api_key()),
+                ("ok".to_string(), ok.api_key()),
+            ]),
+            ..Default::default()
+        };
+        let state = mock::test_state(config);
+        let model = |first: &str, fallbacks: &[&str]| ModelInfo {
+            fallbacks: fallbacks.iter().map(|id| target(id)).collect(),
+            ..mock::model("m", first)
+        };
+
+        // Connect errors, 429 and 5xx move on to the next target
+        let model_info =
+            model("closed", &["rate_limited", "unavailable", "ok", "broken"]);
+        let res = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(
+            body.contains("\"Hel\"") && body.contains("\"lo\""),
+            "{body}"
+        );
+        assert_eq!(rate_limited.requests().len(), 1);
+        assert_eq!(unavailable.requests().len(), 1);
+        assert_eq!(ok.requests().len(), 1);
+        assert!(broken.requests().is_empty());
+
+        // Other errors are returned as they are
+        let model_info = model("bad_request", &["ok"]);
+        let err = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("400"), "{err}");
+        assert_eq!(ok.requests().len(), 1);
+
+        // Once the first token is streamed, a broken stream stays broken
+        let model_info = model("broken", &["ok"]);
+        let res = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .is_err());
+        assert_eq!(ok.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_request_url() {
+        let url = |provider: ApiKeyProvider, base_url: Option<&str>| {
+            let info = ApiKeyInfo {
+                provider: provider.clone(),
+                base_url: base_url.map(str::to_string),
+                ..Default::default()
+            };
+            request_url(&provider, info.url(), "m", "k", false)
+        };
+        for (provider, default) in [
+            (
+                ApiKeyProvider::Aliyun,
+                "https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions",
+            ),
+            (
+                ApiKeyProvider::Tencent,
+                "https://api.lkeap.cloud.tencent.com/v1/chat/completions",
+            ),
+            (
+                ApiKeyProvider::Bytedance,
+                "https://ark.cn-beijing.volces.com/api/v3/chat/completions",
+            ),
+            (
+                ApiKeyProvider::DeepSeek,
+                "https://api.deepseek.com/chat/completions",
+            ),
+            (
+                ApiKeyProvider::Siliconflow,
+                "https://api.siliconflow.cn/v1/chat/completions",
+            ),
+            (
+                ApiKeyProvider::Google,
+                "https://generativelanguage.googleapis.com/v1beta/models/m:generateContent?key=k",
+            ),
+        ] {
+            assert_eq!(url(provider.clone(), None), default);
+            // The trailing slash of an override is dropped
+            let expected = match provider {
+                ApiKeyProvider::Google => "https://gateway.example/v1/models/m:generateContent?key=k",
+                _ => "https://gateway.example/v1/chat/completions",
+            };
+            assert_eq!(
+                url(provider, Some("https://gateway.example/v1/")),
+                expected
+            );
         }
-    };
-    Ok(res)
+        assert_eq!(
+            request_url(
+                &ApiKeyProvider::Google,
+                "https://gateway.example/v1",
+                "m",
+                "k",
+                true
+            ),
+            "https://gateway.example/v1/models/m:streamGenerateContent?alt=sse&key=k"
+        );
+
+        // A custom provider has its full url, even with a base_url
+        let custom = ApiKeyProvider::Custom("http://10.0.0.5/v1/chat".to_string());
+        assert_eq!(
+            url(custom, Some("https://ignored.example")),
+            "http://10.0.0.5/v1/chat"
+        );
+    }
 }
@@ -0,0 +1,298 @@
+//! Share one upstream call among identical requests in flight, see [`ModelInfo::coalesce`]
+//!
+//! [`ModelInfo::coalesce`]: crate::ModelInfo::coalesce
+use std::{collections::HashMap, future::Future, pin::pin, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::api::provider::error::UpstreamError;
+
+/// Set on the responses that were shared with an identical request already in flight
+pub(crate) const COALESCED_HEADER: &str = "x-uni-llm-coalesced";
+
+/// The calls in flight by the normalized request
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+impl SingleFlight {
+    /// Respond with the result of `call`, or with the one of an identical request in flight.
+    ///
+    /// `call` runs on its own task so that every waiter gets the whole response,
+    /// even when the client that started it went away
+    pub(crate) async fn run<F>(&self, key: String, call: F) -> anyhow::Result<Response>
+    where
+        F: Future<Output = anyhow::Result<Response>> + Send + 'static,
+    {
+        let (flight, leader) = {
+            let mut flights = self.flights.lock();
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), None),
+                None => {
+                    let flight = Arc::<Flight>::default();
+                    flights.insert(key.clone(), flight.clone());
+                    let leader = Leader {
+                        flights: self.flights.clone(),
+                        key,
+                        flight: flight.clone(),
+                    };
+                    (flight, Some(leader))
+                }
+            }
+        };
+        let coalesced = match leader {
+            Some(leader) => {
+                tokio::spawn(leader.drive(call));
+                false
+            }
+            None => true,
+        };
+        flight.response(coalesced).await
+    }
+}
+
+/// Why a call failed, shared by every waiter
+#[derive(Clone)]
+enum Failure {
+    /// Kept whole, so the status and `Retry-After` reach the waiters
+    Upstream(Arc<UpstreamError>),
+    Other(String),
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<UpstreamError>() {
+            Ok(err) => Failure::Upstream(Arc::new(err)),
+            Err(err) => Failure::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<Failure> for anyhow::Error {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::Upstream(err) => anyhow::Error::new(err),
+            Failure::Other(err) => anyhow!(err),
+        }
+    }
+}
+
+#[derive(Default)]
+struct FlightState {
+    head: Option<Result<(StatusCode, HeaderMap), Failure>>,
+    chunks: Vec<Bytes>,
+    /// Set once the body ended, with the error that broke it if any
+    end: Option<Option<String>>,
+}
+
+/// The response of a call, kept whole so late joiners replay it from the start
+#[derive(Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+    /// Wakes the waiters when the response makes progress
+    notify: Notify,
+}
+
+impl Flight {
+    fn update(&self, f: impl FnOnce(&mut FlightState)) {
+        f(&mut self.state.lock());
+        self.notify.notify_waiters();
+    }
+
+    async fn response(self: Arc<Self>, coalesced: bool) -> anyhow::Result<Response> {
+        let head = loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            if let Some(head) = self.state.lock().head.clone() {
+                break head;
+            }
+            notified.await;
+        };
+        let (status, headers) = head?;
+        let mut res = Response::new(Body::from_stream(self.replay()));
+        *res.status_mut() = status;
+        *res.headers_mut() = headers;
+        if coalesced {
+            res.headers_mut()
+                .insert(COALESCED_HEADER, HeaderValue::from_static("true"));
+        }
+        Ok(res)
+    }
+
+    fn replay(self: Arc<Self>) -> impl Stream<Item = anyhow::Result<Bytes>> {
+        async_stream::stream! {
+            let mut next = 0;
+            loop {
+                let mut notified = pin!(self.notify.notified());
+                notified.as_mut().enable();
+                let (chunks, end) = {
+                    let state = self.state.lock();
+                    (state.chunks[next..].to_vec(), state.end.clone())
+                };
+                next += chunks.len();
+                for chunk in chunks {
+                    yield Ok(chunk);
+                }
+                match end {
+                    Some(Some(e)) => {
+                        yield Err(anyhow!(e));
+                        break;
+                    }
+                    Some(None) => break,
+                    None => notified.await,
+                }
+            }
+        }
+    }
+}
+
+/// Runs the call of a [`Flight`], which is ended and forgotten when this is dropped
+struct Leader {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    key: String,
+    flight: Arc<Flight>,
+}
+
+impl Leader {
+    async fn drive(self, call: impl Future<Output = anyhow::Result<Response>>) {
+        let res = match call.await {
+            Ok(res) => res,
+            Err(e) => {
+                self.forget();
+                self.flight.update(|state| state.head = Some(Err(e.into())));
+                return;
+            }
+        };
+        let (parts, body) = res.into_parts();
+        self.flight
+            .update(|state| state.head = Some(Ok((parts.status, parts.headers))));
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => self.flight.update(|state| state.chunks.push(chunk)),
+                Err(e) => {
+                    self.forget();
+                    self.flight
+                        .update(|state| state.end = Some(Some(e.to_string())));
+                    return;
+                }
+            }
+        }
+        // Requests arriving from now on make a call of their own
+        self.forget();
+        self.flight.update(|state| state.end = Some(None));
+    }
+
+    fn forget(&self) {
+        let mut flights = self.flights.lock();
+        if flights
+            .get(&self.key)
+            .is_some_and(|flight| Arc::ptr_eq(flight, &self.flight))
+        {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.forget();
+        // Never leave the waiters hanging, even if the call panicked
+        self.flight.update(|state| {
+            state.head.get_or_insert_with(|| {
+                Err(Failure::Other(
+                    "the coalesced request was cancelled".to_string(),
+                ))
+            });
+            state.end.get_or_insert_with(|| {
+                Some("the coalesced request was cancelled".to_string())
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{body::Body, response::Response};
+    use reqwest::StatusCode;
+
+    use crate::api::provider::error::UpstreamError;
+
+    use super::{SingleFlight, COALESCED_HEADER};
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let flights = SingleFlight::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let call = |body: &'static str| {
+            let calls = calls.clone();
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Response::new(Body::from(body)))
+            }
+        };
+        let (first, second, other) = tokio::join!(
+            flights.run("a".to_string(), call("answer")),
+            flights.run("a".to_string(), call("duplicate")),
+            flights.run("b".to_string(), call("other")),
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let (first, second, other) = (first.unwrap(), second.unwrap(), other.unwrap());
+        assert!(first.headers().get(COALESCED_HEADER).is_none());
+        assert!(second.headers().get(COALESCED_HEADER).is_some());
+        for (res, expected) in [(first, "answer"), (second, "answer"), (other, "other")] {
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+
+        // A finished call is not shared anymore
+        flights.run("a".to_string(), call("again")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // The waiters get the error of the provider itself, not just its text
+        let failing = || async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err(UpstreamError::Status {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after: Some(Duration::from_secs(7)),
+                body: "slow down".to_string(),
+            }
+            .into())
+        };
+        let (first, second) = tokio::join!(
+            flights.run("c".to_string(), failing()),
+            flights.run("c".to_string(), failing()),
+        );
+        for err in [first.unwrap_err(), second.unwrap_err()] {
+            assert!(UpstreamError::is_retryable_error(&err));
+            assert!(matches!(
+                UpstreamError::of(&err),
+                Some(UpstreamError::Status {
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                    retry_after: Some(retry_after),
+                    ..
+                }) if *retry_after == Duration::from_secs(7)
+            ));
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Continue a stream that broke after partial output on a fallback target,
+//! see [`ModelInfo::continuation`]
+use axum::{body::Body, response::Response};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{common::tokenizer::UsageEstimator, SharedStateRef};
+
+use super::{
+    chat::dispatch_target,
+    config::{ContinuationMode, ModelInfo, ModelTarget},
+    message::{
+        gen_ollama_think_end_message, OllamaChatRequest, ReqMessage, RespMessage, Role,
+    },
+};
+
+pub(super) const THINK_START: &str = "<think>";
+pub(super) const THINK_END: &str = "</think>";
+/// The user turn appended with [`ContinuationMode::Prompt`]
+const CONTINUE_PROMPT: &str =
+    "Your previous reply was cut off. Continue it exactly where \
+it stopped, without repeating anything or adding any preamble.";
+
+/// A line of the ollama NDJSON stream, see [`super::message::OllamaChatResponse`]
+#[derive(Deserialize)]
+struct StreamLine {
+    #[serde(default)]
+    message: Option<RespMessage>,
+    #[serde(default)]
+    done: bool,
+}
+
+/// Split a stream of chunks into NDJSON lines
+#[derive(Default)]
+pub(super) struct Lines {
+    pending: Vec<u8>,
+}
+
+impl Lines {
+    /// The complete lines of `chunk` including their `\n`, the rest waits for the next chunk
+    pub(super) fn feed(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            lines.push(self.pending.drain(..=end).collect());
+        }
+        lines
+    }
+}
+
+fn parse_line(line: &[u8]) -> Option<(String, bool)> {
+    let line = serde_json::from_slice::<StreamLine>(line).ok()?;
+    Some((
+        line.message.map(|m| m.content).unwrap_or_default(),
+        line.done,
+    ))
+}
+
+/// What the client has received so far
+#[derive(Default)]
+struct Transcript {
+    lines: Lines,
+    thinking: bool,
+    answer: String,
+    done: bool,
+}
+
+impl Transcript {
+    fn feed(&mut self, chunk: &[u8]) {
+        for line in self.lines.feed(chunk) {
+            let Some((content, done)) = parse_line(&line) else {
+                continue;
+            };
+            match content.as_str() {
+                THINK_START => self.thinking = true,
+                THINK_END => self.thinking = false,
+                _ if self.thinking => {}
+                _ => self.answer.push_str(&content),
+            }
+            self.done |= done;
+        }
+    }
+}
+
+/// Drops the reasoning of a continuation, the client already got one
+#[derive(Default)]
+struct ReasoningFilter {
+    lines: Lines,
+    thinking: bool,
+    /// The tokens of the answer the continuation got as its prefix
+    answer_tokens: u32,
+}
+
+impl ReasoningFilter {
+    fn filter(&mut self, chunk: &[u8]) -> Bytes {
+        let mut kept = Vec::with_capacity(chunk.len());
+        for line in self.lines.feed(chunk) {
+            let content = parse_line(&line).map(|(content, _)| content);
+            match content.as_deref() {
+                Some(THINK_START) => self.thinking = true,
+                Some(THINK_END) => self.thinking = false,
+                _ if self.thinking => {}
+                _ => kept.extend_from_slice(&self.count_answer(line)),
+            }
+        }
+        Bytes::from(kept)
+    }
+
+    /// Move the answer before the break from the prompt to the completion
+    /// in the usage of the done line, so that it covers the whole answer
+    fn count_answer(&self, line: Vec<u8>) -> Vec<u8> {
+        let Ok(mut resp) = serde_json::from_slice::<Value>(&line) else {
+            return line;
+        };
+        if self.answer_tokens == 0 || resp["done"] != true {
+            return line;
+        }
+        let count = |resp: &Value, field: &str| resp[field].as_u64().unwrap_or(0);
+        let prompt = count(&resp, "prompt_eval_count");
+        let completion = count(&resp, "eval_count");
+        let answer = self.answer_tokens as u64;
+        resp["prompt_eval_count"] = prompt.saturating_sub(answer).into();
+        resp["eval_count"] = (completion + answer).into();
+        format!("{resp}\n").into_bytes()
+    }
+}
+
+/// The request continuing `answer`, the partial answer the client received
+fn continuation_payload(
+    payload: &OllamaChatRequest,
+    mode: ContinuationMode,
+    answer: &str,
+) -> OllamaChatRequest {
+    let mut payload = payload.clone();
+    // Nothing to continue when it broke while reasoning, so just ask again
+    if answer.is_empty() {
+        return payload;
+    }
+    payload.messages.push(ReqMessage {
+        role: Role::Assistant,
+        content: answer.to_string(),
+        images: None,
+        tool_calls: None,
+    });
+    if mode == ContinuationMode::Prompt {
+        payload.messages.push(ReqMessage {
+            role: Role::User,
+            content: CONTINUE_PROMPT.to_string(),
+            images: None,
+            tool_calls: None,
+        });
+    }
+    payload
+}
+
+/// Splice a continuation from one of `targets` into the stream of `res` when it breaks
+/// before it is done, for as long as there are targets left
+#[allow(clippy::too_many_arguments)]
+pub(super) fn continue_on_break(
+    state: &SharedStateRef,
+    payload: &OllamaChatRequest,
+    model_info: &ModelInfo,
+    mode: ContinuationMode,
+    targets: Vec<ModelTarget>,
+    estimator: UsageEstimator,
+    client: Option<u64>,
+    started: Instant,
+    res: Response,
+) -> Response {
+    let state = state.clone();
+    let payload = payload.clone();
+    let model_info = model_info.clone();
+    res.map(|body| {
+        let mut stream = body.into_data_stream().boxed();
+        Body::from_stream(async_stream::stream! {
+            let mut transcript = Transcript::default();
+            let mut targets = targets.into_iter();
+            let mut filter = None::<ReasoningFilter>;
+            loop {
+                let err = match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        transcript.feed(&chunk);
+                        match filter.as_mut() {
+                            Some(filter) => yield Ok(filter.filter(&chunk)),
+                            None => yield Ok(chunk),
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => Some(anyhow::Error::from(e)),
+                    None if transcript.done => break,
+                    None => None,
+                };
+                let reason = err.as_ref().map_or("ended early".to_string(), |e| e.to_string());
+                let next_payload = continuation_payload(&payload, mode, &transcript.answer);
+                // The continuation is prompted with the answer so far, and the turn asking for it
+                let answer_tokens = estimator.count_tokens(&transcript.answer);
+                let added_tokens = next_payload.messages[payload.messages.len()..]
+                    .iter()
+                    .map(|m| estimator.count_tokens(&m.content))
+                    .sum();
+                let next_estimator = estimator.with_more_prompt(added_tokens);
+                let mut continued = false;
+                for target in targets.by_ref() {
+                    tracing::warn!(
+                        "Stream continuation: {} broke ({reason}) after {} bytes of answer, \
+                        continue on {}/{}",
+                        payload.model,
+                        transcript.answer.len(),
+                        target.api_key_id,
+                        target.name
+                    );
+                    let res = dispatch_target(
+                        &state,
+                        &next_payload,
+                        &model_info,
+                        &target,
+                        next_estimator.clone(),
+                        client,
+                        started,
+                    );
+                    match res.await {
+                        Ok(res) => {
+                            stream = res.into_body().into_data_stream().boxed();
+                            continued = true;
+                            break;
+                        }
+                        Err(e) => tracing::warn!("Stream continuation failed: {e}"),
+                    }
+                }
+                if !continued {
+                    if let Some(err) = err {
+                        yield Err(err);
+                    }
+                    break;
+                }
+                // The reasoning of a continuation never reaches the client
+                let client_thinking = transcript.thinking && filter.is_none();
+                transcript.thinking = false;
+                if client_thinking {
+                    let think_end = gen_ollama_think_end_message(&payload.model);
+                    yield Ok(Bytes::from(format!("{think_end}\n")));
+                }
+                filter = Some(ReasoningFilter {
+                    answer_tokens,
+                    ..Default::default()
+                });
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::Value;
+
+    use crate::{
+        api::{
+            mock::{self, MockProvider},
+            uni_ollama::{
+                chat::dispatch,
+                message::{
+                    gen_ollama_message, gen_ollama_think_end_message,
+                    gen_ollama_think_start_message, RespMessage, Role,
+                },
+            },
+        },
+        ContinuationMode, ModelInfo, ModelTarget, UniModelsInfo,
+    };
+
+    use super::{ReasoningFilter, Transcript};
+
+    fn line(content: &str) -> String {
+        let msg = RespMessage {
+            role: Role::Assistant,
+            content: content.to_string(),
+            images: None,
+        };
+        format!("{}\n", gen_ollama_message("m", msg))
+    }
+
+    #[test]
+    fn test_splice() {
+        let think = format!(
+            "{}\n{}{}\n",
+            gen_ollama_think_start_message("m"),
+            line("hmm"),
+            gen_ollama_think_end_message("m")
+        );
+        let mut transcript = Transcript::default();
+        // Chunks may split lines anywhere
+        let stream = format!("{think}{}{}", line("Hello"), line(" wor"));
+        let (a, b) = stream.split_at(stream.len() / 2);
+        transcript.feed(a.as_bytes());
+        transcript.feed(b.as_bytes());
+        assert_eq!(transcript.answer, "Hello wor");
+        assert!(!transcript.thinking && !transcript.done);
+
+        let mut filter = ReasoningFilter::default();
+        let answer = line("ld");
+        let kept = filter.filter(format!("{think}{answer}").as_bytes());
+        assert_eq!(kept, answer.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_continue_on_break() {
+        let broken =
+            MockProvider::start(|_| async { mock::broken_stream(&["Hello", " wor"]) })
+                .await;
+        let fallback =
+            MockProvider::start(|_| async { mock::stream(&["ld", "!"]) }).await;
+        let config = UniModelsInfo {
+            api_keys: HashMap::from([
+                ("broken".to_string(), broken.api_key()),
+                ("fallback".to_string(), fallback.api_key()),
+            ]),
+            ..Default::default()
+        };
+        let state = mock::test_state(config);
+        let model_info = ModelInfo {
+            fallbacks: vec![ModelTarget {
+                name: "m".to_string(),
+                api_key_id: "fallback".to_string(),
+            }],
+            continuation: Some(ContinuationMode::Prefix),
+            ..mock::model("m", "broken")
+        };
+        let res = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines = String::from_utf8_lossy(&body)
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        // The fallback continues the partial answer
+        let requests = fallback.requests();
+        let messages = requests[0]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "Hello wor");
+
+        // The client gets one answer with a single done line closing it
+        let answer = lines
+            .iter()
+            .filter_map(|line| line["message"]["content"].as_str())
+            .collect::<String>();
+        assert_eq!(answer, "Hello world!");
+        let (last, others) = lines.split_last().unwrap();
+        assert!(others.iter().all(|line| line["done"] == false));
+        assert_eq!(last["done"], true);
+        // The usage covers the whole answer, 3 tokens before the break and 1 after it
+        assert_eq!(last["prompt_eval_count"], 1);
+        assert_eq!(last["eval_count"], 4);
+        assert_eq!(last["total_duration"], 5);
+    }
+}
@@ -0,0 +1,182 @@
+//! The formats the config file may be written in, told by its extension
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use toml_edit::{DocumentMut, Item, Table};
+
+/// The format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    /// `.json`
+    Json,
+    /// `.toml`
+    Toml,
+    /// `.yaml` or `.yml`
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The names of the config file looked for in the config directory, in order of preference
+    pub const FILE_NAMES: [&'static str; 4] =
+        ["config.json", "config.toml", "config.yaml", "config.yml"];
+
+    /// The format of the file at `path`
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            _ => {
+                bail!("Unknown config format of {path:?}, expected .json, .toml or .yaml")
+            }
+        }
+    }
+
+    /// Parse `content`, calling `unknown` with the path of every field `T` does not know
+    pub(crate) fn parse<T: DeserializeOwned>(
+        self,
+        content: &str,
+        mut unknown: impl FnMut(String),
+    ) -> anyhow::Result<T> {
+        let mut on_ignored = |field: serde_ignored::Path<'_>| unknown(field.to_string());
+        Ok(match self {
+            Self::Json => serde_ignored::deserialize(
+                &mut serde_json::Deserializer::from_str(content),
+                &mut on_ignored,
+            )?,
+            Self::Toml => serde_ignored::deserialize(
+                toml::Deserializer::new(content),
+                &mut on_ignored,
+            )?,
+            Self::Yaml => serde_ignored::deserialize(
+                serde_yaml_ng::Deserializer::from_str(content),
+                &mut on_ignored,
+            )?,
+        })
+    }
+
+    /// Write `value` in this format. Comments of the original file are not kept
+    pub(crate) fn to_string(self, value: &impl Serialize) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(value)?,
+            Self::Toml => toml::to_string_pretty(value).context("Write as toml")?,
+            Self::Yaml => serde_yaml_ng::to_string(value)?,
+        })
+    }
+
+    /// Write `new` over `content`, a file holding `old`.
+    /// A toml file keeps the comments and the layout of the entries that did not change,
+    /// a yaml file is written anew and loses its comments
+    pub(crate) fn rewrite<T: Serialize>(
+        self,
+        content: &str,
+        old: &T,
+        new: &T,
+    ) -> anyhow::Result<String> {
+        match self {
+            Self::Json => self.to_string(new),
+            Self::Toml => {
+                let mut document =
+                    content.parse::<DocumentMut>().context("Parse as toml")?;
+                let written = self
+                    .to_string(new)?
+                    .parse::<DocumentMut>()
+                    .context("Parse as toml")?;
+                merge_toml(
+                    document.as_table_mut(),
+                    &serde_json::to_value(old)?,
+                    &serde_json::to_value(new)?,
+                    written.as_table().clone(),
+                );
+                Ok(document.to_string())
+            }
+            Self::Yaml => {
+                tracing::warn!("Rewrite the yaml config, which drops its comments");
+                self.to_string(new)
+            }
+        }
+    }
+}
+
+/// Take the entries of `written` into `table` where the value changed from `old` to `new`,
+/// the others stay as they are written in `table`. So do the fields `old` does not know,
+/// along with the comments before them
+fn merge_toml(
+    table: &mut Table,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    written: Table,
+) {
+    table.retain(|key, _| written.contains_key(key) || old.get(key).is_none());
+    for (key, item) in written {
+        let (old, new) = (&old[key.as_str()], &new[key.as_str()]);
+        match table.get_mut(&key) {
+            Some(_) if old == new => {}
+            Some(Item::Table(current)) if item.is_table() => {
+                let item = item.into_table().expect("checked above");
+                merge_toml(current, old, new, item);
+            }
+            Some(current) => *current = item,
+            None => {
+                table.insert(&key, item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::api::uni_ollama::config::UniModelsInfo;
+
+    use super::ConfigFormat;
+
+    #[test]
+    fn test_formats() {
+        let models_info = UniModelsInfo::default();
+        let json = serde_json::to_value(&models_info).unwrap();
+        for path in ["config.json", "config.toml", "config.yml"] {
+            let format = ConfigFormat::of(Path::new(path)).unwrap();
+            let content = format.to_string(&models_info).unwrap();
+            let mut unknown = Vec::new();
+            let parsed: UniModelsInfo =
+                format.parse(&content, |f| unknown.push(f)).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), json, "{path}");
+            assert!(unknown.is_empty());
+        }
+
+        let toml = "
+            # Comments are welcome
+            typo = 1
+            [api_keys.deepseek]
+            api_key = 'env:DEEPSEEK_KEY'
+            provider = 'DeepSeek'
+            need_proxy = false
+            [models.deepseek-chat]
+            name = 'deepseek-chat'
+            api_key_id = 'deepseek'
+        ";
+        let mut unknown = Vec::new();
+        let parsed: UniModelsInfo =
+            ConfigFormat::Toml.parse(toml, |f| unknown.push(f)).unwrap();
+        assert_eq!(unknown, ["typo"]);
+        assert_eq!(parsed.api_keys["deepseek"].api_key, ["env:DEEPSEEK_KEY"]);
+        assert!(ConfigFormat::of(Path::new("config.ini")).is_err());
+
+        // Rewriting keeps the comments of what did not change
+        let mut changed = parsed.clone();
+        changed.models.get_mut("deepseek-chat").unwrap().name = "deepseek-v3".to_string();
+        changed.api_keys.get_mut("deepseek").unwrap().need_proxy = true;
+        let rewritten = ConfigFormat::Toml.rewrite(toml, &parsed, &changed).unwrap();
+        assert!(rewritten.contains("# Comments are welcome"));
+        assert!(rewritten.contains("api_key = 'env:DEEPSEEK_KEY'"));
+        let reparsed: UniModelsInfo =
+            ConfigFormat::Toml.parse(&rewritten, |_| {}).unwrap();
+        assert_eq!(
+            serde_json::to_value(&reparsed).unwrap(),
+            serde_json::to_value(&changed).unwrap()
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Limit the requests in flight to a provider, the excess waits in a queue
+//! taking turns across clients, see [`ConcurrencyConfig`]
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{body::Body, response::Response};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time::Instant};
+
+use crate::api::provider::error::{TimeoutKind, UpstreamError};
+
+use super::config::{ConcurrencyConfig, SelectedApiKeyInfo};
+
+/// The gates by the key in [`crate::UniModelsInfo::api_keys`]
+#[derive(Default)]
+pub(crate) struct Gates {
+    gates: Mutex<HashMap<String, Arc<Gate>>>,
+}
+
+impl Gates {
+    pub(crate) fn get(&self, api_key_id: &str) -> Arc<Gate> {
+        self.gates
+            .lock()
+            .entry(api_key_id.to_string())
+            .or_insert_with(|| {
+                Arc::new(Gate {
+                    api_key_id: api_key_id.to_string(),
+                    state: Default::default(),
+                    notify: Notify::new(),
+                })
+            })
+            .clone()
+    }
+}
+
+/// Waiting requests, served one client at a time in turn
+#[derive(Default)]
+struct FairQueue {
+    next_ticket: u64,
+    len: usize,
+    /// The clients with waiting requests, the front one is served next
+    clients: VecDeque<u64>,
+    tickets: HashMap<u64, VecDeque<u64>>,
+}
+
+impl FairQueue {
+    fn push(&mut self, client: u64) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        let tickets = self.tickets.entry(client).or_default();
+        if tickets.is_empty() {
+            self.clients.push_back(client);
+        }
+        tickets.push_back(ticket);
+        self.len += 1;
+        ticket
+    }
+
+    fn front(&self) -> Option<u64> {
+        let client = self.clients.front()?;
+        self.tickets.get(client)?.front().copied()
+    }
+
+    /// Remove the front ticket, its client goes to the back of the line
+    fn pop(&mut self) {
+        let Some(client) = self.clients.pop_front() else {
+            return;
+        };
+        if let Some(tickets) = self.tickets.get_mut(&client) {
+            tickets.pop_front();
+            self.len -= 1;
+            if tickets.is_empty() {
+                self.tickets.remove(&client);
+            } else {
+                self.clients.push_back(client);
+            }
+        }
+    }
+
+    fn remove(&mut self, client: u64, ticket: u64) {
+        let Some(tickets) = self.tickets.get_mut(&client) else {
+            return;
+        };
+        if let Some(pos) = tickets.iter().position(|t| *t == ticket) {
+            tickets.remove(pos);
+            self.len -= 1;
+        }
+        if tickets.is_empty() {
+            self.tickets.remove(&client);
+            self.clients.retain(|c| *c != client);
+        }
+    }
+}
+
+#[derive(Default)]
+struct GateState {
+    in_flight: usize,
+    /// By the value of the key rather than its index, which changes when keys are
+    /// added, removed or reordered while their requests are in flight
+    in_flight_per_key: HashMap<String, usize>,
+    queue: FairQueue,
+}
+
+/// Selects a key among the ones with room, see [`Gate::acquire`]
+type Select<'a> = dyn FnMut(&dyn Fn(&str) -> bool) -> anyhow::Result<Option<SelectedApiKeyInfo>>
+    + Send
+    + 'a;
+
+/// Limits the requests in flight to the keys of an [`crate::ApiKeyInfo`]
+pub(crate) struct Gate {
+    api_key_id: String,
+    state: Mutex<GateState>,
+    /// Wakes the waiting requests when a slot is released or the line moves
+    notify: Notify,
+}
+
+impl Gate {
+    /// Take a slot if one is free and pick a key with `select`.
+    /// `select` runs without the lock held, as it locks the model config itself
+    fn try_take(
+        &self,
+        config: &ConcurrencyConfig,
+        select: &mut Select<'_>,
+    ) -> anyhow::Result<Option<SelectedApiKeyInfo>> {
+        loop {
+            let per_key = {
+                let mut state = self.state.lock();
+                if config
+                    .max_concurrency
+                    .is_some_and(|max| state.in_flight >= max as usize)
+                {
+                    return Ok(None);
+                }
+                // Reserved until a key is picked
+                state.in_flight += 1;
+                state.in_flight_per_key.clone()
+            };
+            let has_room = |key: &str| {
+                config.max_per_key.is_none_or(|max| {
+                    per_key.get(key).copied().unwrap_or(0) < max as usize
+                })
+            };
+            let selected = select(&has_room);
+            let mut state = self.state.lock();
+            let selected = match selected {
+                Ok(Some(selected)) => selected,
+                other => {
+                    state.in_flight -= 1;
+                    return other;
+                }
+            };
+            let count = state
+                .in_flight_per_key
+                .entry(selected.api_key.clone())
+                .or_default();
+            // Another request took the last slot of the key meanwhile, pick again
+            if config.max_per_key.is_some_and(|max| *count >= max as usize) {
+                state.in_flight -= 1;
+                continue;
+            }
+            *count += 1;
+            return Ok(Some(selected));
+        }
+    }
+
+    /// Wait for a free slot, then pick a key with `select`, which gets told the keys with room
+    /// and fails if no key is available at all.
+    /// The slot is held until the returned [`Permit`] is dropped
+    pub(crate) async fn acquire(
+        self: &Arc<Self>,
+        config: &ConcurrencyConfig,
+        client: Option<u64>,
+        select: &mut Select<'_>,
+    ) -> anyhow::Result<(SelectedApiKeyInfo, Permit)> {
+        let client = client.unwrap_or_default();
+        if self.state.lock().queue.len == 0 {
+            if let Some(selected) = self.try_take(config, select)? {
+                return Ok(self.permit(selected));
+            }
+        }
+        let ticket = {
+            let mut state = self.state.lock();
+            if state.queue.len >= config.max_queue {
+                return Err(UpstreamError::QueueFull(self.api_key_id.clone()).into());
+            }
+            Ticket {
+                gate: self,
+                client,
+                ticket: state.queue.push(client),
+            }
+        };
+        let timeout = Duration::from_millis(config.queue_timeout_ms);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            if self.state.lock().queue.front() == Some(ticket.ticket) {
+                if let Some(selected) = self.try_take(config, select)? {
+                    ticket.pop();
+                    return Ok(self.permit(selected));
+                }
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                tracing::warn!(
+                    "Waited {timeout:?} for a free slot of {}",
+                    self.api_key_id
+                );
+                return Err(UpstreamError::Timeout(TimeoutKind::Queue, timeout).into());
+            }
+        }
+    }
+
+    fn permit(
+        self: &Arc<Self>,
+        selected: SelectedApiKeyInfo,
+    ) -> (SelectedApiKeyInfo, Permit) {
+        let permit = Permit {
+            gate: self.clone(),
+            api_key: selected.api_key.clone(),
+        };
+        (selected, permit)
+    }
+}
+
+/// A place in the queue of a [`Gate`], left when dropped,
+/// so that a cancelled request does not hold up the ones behind it
+struct Ticket<'a> {
+    gate: &'a Gate,
+    client: u64,
+    ticket: u64,
+}
+
+impl Ticket<'_> {
+    /// Leave the front of the queue, the client goes to the back of the line
+    fn pop(&self) {
+        let mut state = self.gate.state.lock();
+        if state.queue.front() == Some(self.ticket) {
+            state.queue.pop();
+        }
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.gate
+            .state
+            .lock()
+            .queue
+            .remove(self.client, self.ticket);
+        // The next one in line may fit now
+        self.gate.notify.notify_waiters();
+    }
+}
+
+/// A slot of a [`Gate`], released when dropped
+pub(crate) struct Permit {
+    gate: Arc<Gate>,
+    api_key: String,
+}
+
+impl Permit {
+    /// Hold the slot until the body of `res` is consumed
+    pub(crate) fn attach(self, res: Response) -> Response {
+        res.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let _ = &self;
+                chunk
+            }))
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        {
+            let mut state = self.gate.state.lock();
+            state.in_flight = state.in_flight.saturating_sub(1);
+            if let Some(count) = state.in_flight_per_key.get_mut(&self.api_key) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    state.in_flight_per_key.remove(&self.api_key);
+                }
+            }
+        }
+        self.gate.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, time::Duration};
+
+    use futures::poll;
+
+    use crate::api::uni_ollama::config::{ConcurrencyConfig, SelectedApiKeyInfo};
+
+    use super::{FairQueue, Gates};
+
+    #[test]
+    fn test_fair_queue() {
+        let mut queue = FairQueue::default();
+        let a1 = queue.push(1);
+        let a2 = queue.push(1);
+        let b1 = queue.push(2);
+        // Client 2 is served before the second request of client 1
+        let mut order = Vec::new();
+        while let Some(ticket) = queue.front() {
+            order.push(ticket);
+            queue.pop();
+        }
+        assert_eq!(order, [a1, b1, a2]);
+        assert_eq!(queue.len, 0);
+
+        let a = queue.push(1);
+        let b = queue.push(2);
+        queue.remove(1, a);
+        assert_eq!(queue.front(), Some(b));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter() {
+        let config = ConcurrencyConfig {
+            max_concurrency: Some(1),
+            max_per_key: None,
+            max_queue: 8,
+            queue_timeout_ms: 1000,
+        };
+        let gate = Gates::default().get("k");
+        let mut select = |_: &dyn Fn(&str) -> bool| {
+            Ok(Some(SelectedApiKeyInfo {
+                index: 0,
+                api_key: "a".to_string(),
+                provider: Default::default(),
+                need_proxy: false,
+            }))
+        };
+        let (_, held) = gate.acquire(&config, None, &mut select).await.unwrap();
+
+        // A waiter gives up, like a client disconnecting or the loser of a hedge
+        {
+            let mut select = select;
+            let waiter = gate.acquire(&config, Some(1), &mut select);
+            assert!(poll!(pin!(waiter)).is_pending());
+        }
+        assert_eq!(gate.state.lock().queue.len, 0);
+
+        drop(held);
+        let started = tokio::time::Instant::now();
+        let next = gate.acquire(&config, Some(2), &mut select).await;
+        assert!(next.is_ok());
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_per_key_by_value() {
+        let config = ConcurrencyConfig {
+            max_concurrency: None,
+            max_per_key: Some(1),
+            max_queue: 8,
+            queue_timeout_ms: 1000,
+        };
+        let gate = Gates::default().get("k");
+        let first_with_room = |keys: [&'static str; 2]| {
+            move |has_room: &dyn Fn(&str) -> bool| {
+                let index = keys.iter().position(|key| has_room(key));
+                Ok(index.map(|index| SelectedApiKeyInfo {
+                    index,
+                    api_key: keys[index].to_string(),
+                    provider: Default::default(),
+                    need_proxy: false,
+                }))
+            }
+        };
+        let (held, _permit) = gate
+            .acquire(&config, None, &mut first_with_room(["a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(held.api_key, "a");
+        // The keys were reordered meanwhile, the one in flight is still full
+        let (next, _permit) = gate
+            .acquire(&config, None, &mut first_with_room(["b", "a"]))
+            .await
+            .unwrap();
+        assert_eq!((next.index, next.api_key.as_str()), (0, "b"));
+    }
+}
@@ -0,0 +1,267 @@
+//! Track the health of each api_key, so failing keys are skipped by [`ApiKeyInfo::selected`]
+use std::time::{Duration, Instant};
+
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::api::provider::error::UpstreamError;
+
+use super::config::ApiKeyInfo;
+
+/// The runtime state of a single api_key
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KeyHealth {
+    consecutive_failures: u32,
+    last_failure: Option<Instant>,
+    last_auth_error: Option<Instant>,
+    last_rate_limited: Option<Instant>,
+    /// The key is skipped until then
+    open_until: Option<Instant>,
+    /// Why the key was disabled, it stays disabled until reset or replaced
+    disabled: Option<String>,
+}
+
+impl KeyHealth {
+    pub(crate) fn is_available(&self, now: Instant) -> bool {
+        self.disabled.is_none() && self.open_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// The circuit state of an api_key, see [`KeyState`]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    /// The key is in the rotation
+    Closed,
+    /// The key is cooling down after failures or a rate limit
+    Open,
+    /// The key was rejected by the provider
+    Disabled,
+}
+
+/// The health of an api_key as reported by the admin api
+#[derive(Debug, Serialize)]
+pub(crate) struct KeyState {
+    /// The masked api_key
+    key: String,
+    state: CircuitState,
+    consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_for_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_failure_secs_ago: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_auth_error_secs_ago: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_rate_limited_secs_ago: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled_reason: Option<String>,
+}
+
+/// Keep only the ends of `secret`, enough to tell keys apart in logs and the admin api
+pub(crate) fn mask_secret(secret: &str) -> String {
+    let chars = secret.chars().collect::<Vec<_>>();
+    if chars.len() <= 12 {
+        return "*".repeat(chars.len());
+    }
+    let head = chars[..4].iter().collect::<String>();
+    let tail = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("{head}...{tail}")
+}
+
+impl ApiKeyInfo {
+    /// Carry the runtime state of `old` over to the reloaded `self`.
+    /// The health follows each key by value, so a disabled key stays disabled
+    /// and only new or changed keys start out healthy
+    pub(crate) fn keep_state(&mut self, old: &ApiKeyInfo) {
+        self.health = self
+            .api_key
+            .iter()
+            .map(|key| {
+                let index = old.api_key.iter().position(|old_key| old_key == key);
+                index
+                    .and_then(|index| old.health.get(index).cloned())
+                    .unwrap_or_default()
+            })
+            .collect();
+        // The key that was next stays next, or else the first one after it that is kept
+        let len = old.api_key.len();
+        self.cur_index = (0..len)
+            .map(|i| &old.api_key[(old.cur_index as usize + i) % len])
+            .find_map(|key| self.api_key.iter().position(|new_key| new_key == key))
+            .unwrap_or(0) as u32;
+    }
+
+    /// Update the health of the key at `index` with the result of a request made with it
+    pub(crate) fn record(&mut self, index: usize, result: Result<(), &anyhow::Error>) {
+        let config = self.circuit_breaker.clone().unwrap_or_default();
+        let (Some(health), Some(key)) =
+            (self.health.get_mut(index), self.api_key.get(index))
+        else {
+            // The keys were reloaded in the meantime
+            return;
+        };
+        let now = Instant::now();
+        let err = match result {
+            Ok(()) => {
+                if health.consecutive_failures > 0 {
+                    tracing::info!("Api key {} recovered", mask_secret(key));
+                }
+                health.consecutive_failures = 0;
+                health.open_until = None;
+                return;
+            }
+            Err(e) => e,
+        };
+        // Anything else is not the fault of the key
+        let Some(err) = UpstreamError::of(err) else {
+            return;
+        };
+        match err {
+            UpstreamError::Status { status, .. }
+                if *status == StatusCode::UNAUTHORIZED
+                    || *status == StatusCode::FORBIDDEN =>
+            {
+                tracing::error!("Disable api key {}: {status}", mask_secret(key));
+                health.last_auth_error = Some(now);
+                health.disabled = Some(status.to_string());
+            }
+            UpstreamError::Status {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after,
+                ..
+            } => {
+                let cooldown = retry_after
+                    .unwrap_or(Duration::from_millis(config.rate_limit_cooldown_ms));
+                tracing::warn!(
+                    "Api key {} is rate limited, skip it for {cooldown:?}",
+                    mask_secret(key)
+                );
+                health.last_rate_limited = Some(now);
+                health.open_until = Some(now + cooldown);
+            }
+            err if err.is_retryable() => {
+                health.consecutive_failures += 1;
+                health.last_failure = Some(now);
+                // Once open, a single failure after the cooldown opens the circuit again
+                if health.consecutive_failures >= config.failure_threshold {
+                    let cooldown = Duration::from_millis(config.cooldown_ms);
+                    tracing::warn!(
+                        "Api key {} failed {} times in a row, skip it for {cooldown:?}",
+                        mask_secret(key),
+                        health.consecutive_failures
+                    );
+                    health.open_until = Some(now + cooldown);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the key at `index` can take a request,
+    /// neither disabled in the config or by its failures nor cooling down
+    pub(crate) fn is_usable(&self, index: usize, now: Instant) -> bool {
+        !self.disabled_keys.contains(&index)
+            && self
+                .health
+                .get(index)
+                .is_none_or(|health| health.is_available(now))
+    }
+
+    /// Whether any key is neither disabled nor cooling down
+    pub(crate) fn any_available(&mut self) -> bool {
+        self.health
+            .resize_with(self.api_key.len(), Default::default);
+        let now = Instant::now();
+        (0..self.api_key.len()).any(|i| self.is_usable(i, now))
+    }
+
+    /// Put the key at `index` back into the rotation, returns `false` if there is no such key
+    pub(crate) fn reset_health(&mut self, index: usize) -> bool {
+        self.health
+            .resize_with(self.api_key.len(), Default::default);
+        match self.health.get_mut(index) {
+            Some(health) => {
+                *health = KeyHealth::default();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The health of every key in [`Self::api_key`]
+    pub(crate) fn key_states(&self) -> Vec<KeyState> {
+        let now = Instant::now();
+        let secs_ago = |at: Option<Instant>| at.map(|at| (now - at).as_secs());
+        self.api_key
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let health = self.health.get(i).cloned().unwrap_or_default();
+                let open_for = health.open_until.filter(|until| *until > now);
+                let disabled = match self.disabled_keys.contains(&i) {
+                    true => Some("disabled in the config".to_string()),
+                    false => health.disabled,
+                };
+                KeyState {
+                    key: mask_secret(key),
+                    state: if disabled.is_some() {
+                        CircuitState::Disabled
+                    } else if open_for.is_some() {
+                        CircuitState::Open
+                    } else {
+                        CircuitState::Closed
+                    },
+                    consecutive_failures: health.consecutive_failures,
+                    open_for_ms: open_for.map(|until| (until - now).as_millis() as u64),
+                    last_failure_secs_ago: secs_ago(health.last_failure),
+                    last_auth_error_secs_ago: secs_ago(health.last_auth_error),
+                    last_rate_limited_secs_ago: secs_ago(health.last_rate_limited),
+                    disabled_reason: disabled,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use crate::api::{provider::error::UpstreamError, uni_ollama::config::ApiKeyInfo};
+
+    fn status_error(status: StatusCode, retry_after: Option<Duration>) -> anyhow::Error {
+        UpstreamError::Status {
+            status,
+            retry_after,
+            body: String::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_skip_unhealthy_keys() {
+        let mut info = ApiKeyInfo {
+            api_key: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            ..Default::default()
+        };
+        let a = info.selected().unwrap();
+        info.record(a.index, Err(&status_error(StatusCode::UNAUTHORIZED, None)));
+        let b = info.selected().unwrap();
+        let rate_limited = status_error(StatusCode::TOO_MANY_REQUESTS, None);
+        info.record(b.index, Err(&rate_limited));
+        // Only the healthy key is left
+        for _ in 0..3 {
+            assert_eq!(info.selected().unwrap().api_key, "c");
+        }
+        for _ in 0..3 {
+            info.record(2, Err(&status_error(StatusCode::BAD_GATEWAY, None)));
+        }
+        assert!(info.selected().is_none());
+
+        assert!(info.reset_health(0));
+        assert_eq!(info.selected().unwrap().api_key, "a");
+    }
+}
@@ -0,0 +1,201 @@
+//! Race two targets of a model and keep whichever streams first, see [`ModelInfo::hedge_after_ms`]
+use std::{pin::pin, time::Duration};
+
+use axum::response::Response;
+use futures::future::{select, Either};
+use tokio::time::Instant;
+
+use crate::{
+    api::provider::error::UpstreamError, common::tokenizer::UsageEstimator,
+    SharedStateRef,
+};
+
+use super::{
+    chat::dispatch_target,
+    config::{ModelInfo, ModelTarget},
+    message::OllamaChatRequest,
+};
+
+/// Send the request to the first of `targets`, and to the second one as well once the first
+/// has not produced a token within `delay` or failed.
+/// Keeps whichever yields first and cancels the other
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn hedge(
+    state: &SharedStateRef,
+    payload: &OllamaChatRequest,
+    model_info: &ModelInfo,
+    [first, second]: [&ModelTarget; 2],
+    delay: Duration,
+    estimator: &UsageEstimator,
+    client: Option<u64>,
+    started: Instant,
+) -> anyhow::Result<Response> {
+    // The response is only returned once its first token arrived
+    let request = |target| {
+        dispatch_target(
+            state,
+            payload,
+            model_info,
+            target,
+            estimator.clone(),
+            client,
+            started,
+        )
+    };
+    let first_req = pin!(request(first));
+    let err = match select(first_req, pin!(tokio::time::sleep(delay))).await {
+        Either::Left((Ok(res), _)) => return Ok(res),
+        Either::Left((Err(e), _)) if UpstreamError::is_retryable_error(&e) => e,
+        Either::Left((Err(e), _)) => return Err(e),
+        Either::Right(((), first_req)) => {
+            tracing::info!(
+                "No token from {}/{} of {} within {delay:?}, hedge with {}/{}",
+                first.api_key_id,
+                first.name,
+                payload.model,
+                second.api_key_id,
+                second.name
+            );
+            // Dropping the loser cancels its request
+            return match select(first_req, pin!(request(second))).await {
+                Either::Left((Ok(res), _)) | Either::Right((Ok(res), _)) => Ok(res),
+                Either::Left((Err(e), other)) => {
+                    tracing::warn!(
+                        "Hedged target {}/{} failed: {e}",
+                        first.api_key_id,
+                        first.name
+                    );
+                    other.await
+                }
+                Either::Right((Err(e), other)) => {
+                    tracing::warn!(
+                        "Hedged target {}/{} failed: {e}",
+                        second.api_key_id,
+                        second.name
+                    );
+                    other.await
+                }
+            };
+        }
+    };
+    tracing::warn!(
+        "Target {}/{} of {} failed, fail over to the next one: {err}",
+        first.api_key_id,
+        first.name,
+        payload.model
+    );
+    request(second).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use parking_lot::Mutex;
+
+    use crate::{
+        api::{
+            mock::{self, MockProvider},
+            uni_ollama::chat::dispatch,
+        },
+        ModelInfo, ModelTarget, UniModelsInfo,
+    };
+
+    /// Sets the flag when the request holding it is dropped,
+    /// which the slow target does not finish within the test
+    struct Cancelled(Arc<AtomicBool>);
+
+    impl Drop for Cancelled {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hedge() {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let slow = MockProvider::start(move |_| {
+            let guard = Cancelled(flag.clone());
+            async move {
+                let _guard = guard;
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                mock::stream(&["slow"])
+            }
+        })
+        .await;
+        let fast_at = Arc::new(Mutex::new(Vec::new()));
+        let at = fast_at.clone();
+        let fast = MockProvider::start(move |_| {
+            at.lock().push(Instant::now());
+            async { mock::stream(&["fast"]) }
+        })
+        .await;
+        let config = UniModelsInfo {
+            api_keys: HashMap::from([
+                ("slow".to_string(), slow.api_key()),
+                ("fast".to_string(), fast.api_key()),
+            ]),
+            ..Default::default()
+        };
+        let state = mock::test_state(config);
+        let model = |first: &str, second: &str| ModelInfo {
+            fallbacks: vec![ModelTarget {
+                name: "m".to_string(),
+                api_key_id: second.to_string(),
+            }],
+            hedge_after_ms: Some(200),
+            ..mock::model("m", first)
+        };
+        let body = |res: axum::response::Response| async {
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&body).to_string()
+        };
+
+        // The second target fires after the delay and wins, the first is cancelled
+        let start = Instant::now();
+        let model_info = model("slow", "fast");
+        let res = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(body(res).await.contains("\"fast\""));
+        assert!(fast_at.lock()[0] - start >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !cancelled.load(Ordering::SeqCst) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert_eq!(slow.requests().len(), 1);
+
+        // A first target yielding within the delay is kept alone
+        let model_info = model("fast", "slow");
+        let res = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(body(res).await.contains("\"fast\""));
+        assert_eq!(fast_at.lock().len(), 2);
+        assert_eq!(slow.requests().len(), 1);
+    }
+}
@@ -0,0 +1,228 @@
+//! Fit the chat history into the context window of a model
+use anyhow::bail;
+
+use crate::{
+    common::tokenizer::{count_message_tokens, Tokenizer, TOKENS_PER_REPLY},
+    SharedStateRef,
+};
+
+use super::{
+    config::{HistoryPolicy, ModelInfo},
+    message::{ReqMessage, Role},
+    summarize::summarize_history,
+};
+
+/// The response header reporting how many messages were dropped by [`drop_oldest`]
+pub(crate) const DROPPED_MESSAGES_HEADER: &str = "x-uni-llm-dropped-messages";
+/// The response header reporting how many messages were replaced by a summary
+pub(crate) const SUMMARIZED_MESSAGES_HEADER: &str = "x-uni-llm-summarized-messages";
+
+/// The estimated tokens of each message of a prompt. The rate limiter counts them
+/// before the handler, which reuses them unless it changed the messages since
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PromptTokens(pub Vec<usize>);
+
+impl PromptTokens {
+    pub(crate) fn count(tokenizer: &dyn Tokenizer, messages: &[ReqMessage]) -> Self {
+        Self(
+            messages
+                .iter()
+                .map(|msg| count_message_tokens(tokenizer, msg))
+                .collect(),
+        )
+    }
+
+    /// The estimated prompt tokens, see [`crate::common::tokenizer::count_prompt_tokens`]
+    pub(crate) fn total(&self) -> usize {
+        self.0.iter().sum::<usize>() + TOKENS_PER_REPLY
+    }
+}
+
+/// The outcome of [`fit_history`]
+pub(crate) struct FittedHistory {
+    /// The estimated prompt tokens after fitting
+    pub prompt_tokens: usize,
+    /// Only present if the model has a [`ModelInfo::context_length`]
+    pub dropped: Option<usize>,
+    /// Only present if the model uses [`HistoryPolicy::Summarize`]
+    pub summarized: Option<usize>,
+}
+
+/// Apply the [`HistoryPolicy`] of the model to `messages`,
+/// `counted` are their tokens if they are already known
+pub(crate) async fn fit_history(
+    state: &SharedStateRef,
+    model_id: &str,
+    model_info: &ModelInfo,
+    tokenizer: &dyn Tokenizer,
+    messages: &mut Vec<ReqMessage>,
+    counted: Option<PromptTokens>,
+) -> anyhow::Result<FittedHistory> {
+    let mut tokens = counted
+        .filter(|counted| counted.0.len() == messages.len())
+        .unwrap_or_else(|| PromptTokens::count(tokenizer, messages));
+    let mut fitted = FittedHistory {
+        prompt_tokens: tokens.total(),
+        dropped: None,
+        summarized: None,
+    };
+    let budget = model_info.context_length.map(|len| {
+        len.saturating_sub(model_info.max_output_tokens.unwrap_or(0)) as usize
+    });
+    if let HistoryPolicy::Summarize(config) = &model_info.history_policy {
+        let mut summarized = 0;
+        let threshold = config.threshold.map(|t| t as usize).or(budget);
+        if let Some(threshold) = threshold.filter(|t| fitted.prompt_tokens > *t) {
+            // Dropping the oldest turns is still there as the last resort
+            match summarize_history(state, config, tokenizer, threshold, messages).await {
+                Ok(count) => summarized = count,
+                Err(e) => {
+                    tracing::error!("Failed to summarize history of {model_id}: {e:?}")
+                }
+            }
+            tokens = PromptTokens::count(tokenizer, messages);
+            fitted.prompt_tokens = tokens.total();
+        }
+        fitted.summarized = Some(summarized);
+    }
+    if let Some(budget) = budget {
+        let mut dropped = 0;
+        if fitted.prompt_tokens > budget
+            && model_info.history_policy != HistoryPolicy::Reject
+        {
+            let total;
+            (dropped, total) = drop_oldest(messages, &tokens, budget);
+            tracing::info!(
+                "Dropped {dropped} messages to fit {model_id}: {} -> {total} tokens",
+                fitted.prompt_tokens
+            );
+            fitted.prompt_tokens = total;
+        }
+        if fitted.prompt_tokens > budget {
+            bail!(
+                "The prompt has about {} tokens, which exceeds the prompt budget {budget} of {model_id}",
+                fitted.prompt_tokens
+            );
+        }
+        fitted.dropped = Some(dropped);
+    }
+    Ok(fitted)
+}
+
+/// Split the indexes of `messages` into turns that must be dropped together:
+/// a user message with the assistant replies and tool results that follow it,
+/// so that what is left never starts with an assistant message.
+/// System messages are never part of a turn.
+pub(crate) fn droppable_units(messages: &[ReqMessage]) -> Vec<Vec<usize>> {
+    let mut units: Vec<Vec<usize>> = Vec::new();
+    let mut in_turn = false;
+    for (i, msg) in messages.iter().enumerate() {
+        match msg.role {
+            Role::System => {}
+            Role::User => {
+                in_turn = true;
+                units.push(vec![i]);
+            }
+            // Replies before the first user message form a unit of their own
+            Role::Assistant | Role::Tool if !in_turn => {
+                in_turn = true;
+                units.push(vec![i]);
+            }
+            Role::Assistant | Role::Tool => {
+                units.last_mut().expect("turn exists").push(i);
+            }
+        }
+    }
+    units
+}
+
+/// Drop the oldest turns until `messages` of `tokens` fit into `budget` tokens.
+/// System messages and the latest turn are always kept.
+///
+/// Returns the number of dropped messages and the estimated prompt tokens afterwards.
+pub(crate) fn drop_oldest(
+    messages: &mut Vec<ReqMessage>,
+    tokens: &PromptTokens,
+    budget: usize,
+) -> (usize, usize) {
+    let mut total = tokens.total();
+    if total <= budget {
+        return (0, total);
+    }
+    let mut dropped = vec![false; messages.len()];
+    let units = droppable_units(messages);
+    // The latest unit is what the model should reply to
+    for unit in units.iter().take(units.len().saturating_sub(1)) {
+        if total <= budget {
+            break;
+        }
+        for &i in unit {
+            dropped[i] = true;
+            total -= tokens.0[i];
+        }
+    }
+    let mut iter = dropped.iter();
+    messages.retain(|_| !iter.next().expect("same length as messages"));
+    (dropped.iter().filter(|d| **d).count(), total)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::uni_ollama::{
+            config::TokenizerKind,
+            message::{FunctionCall, ReqMessage, Role, ToolCall},
+        },
+        common::tokenizer::get_tokenizer,
+    };
+
+    use super::{drop_oldest, PromptTokens};
+
+    fn msg(role: Role, content: &str) -> ReqMessage {
+        ReqMessage {
+            role,
+            content: content.to_string(),
+            images: None,
+            tool_calls: None,
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let tokenizer = get_tokenizer(&TokenizerKind::Heuristic).unwrap();
+        let long = "word ".repeat(100);
+        let mut call = msg(Role::Assistant, "");
+        call.tool_calls = Some(vec![ToolCall {
+            id: "0".to_string(),
+            type_: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: serde_json::json!({}),
+            },
+        }]);
+        let mut messages = vec![
+            msg(Role::System, "be brief"),
+            msg(Role::User, &long),
+            call,
+            msg(Role::Tool, &long),
+            msg(Role::Assistant, "done"),
+            msg(Role::User, "thanks"),
+        ];
+        // The whole first turn goes, including the tool call and its result
+        let tokens = PromptTokens::count(tokenizer.as_ref(), &messages);
+        let (dropped, total) = drop_oldest(&mut messages, &tokens, 150);
+        assert_eq!(dropped, 4);
+        assert!(total <= 150);
+        let roles = messages.iter().map(|m| m.role).collect::<Vec<_>>();
+        assert_eq!(roles, [Role::System, Role::User]);
+
+        // The system message and the latest turn survive any budget
+        messages.insert(1, msg(Role::User, &long));
+        messages.insert(2, msg(Role::Assistant, &long));
+        let tokens = PromptTokens::count(tokenizer.as_ref(), &messages);
+        let (dropped, _) = drop_oldest(&mut messages, &tokens, 0);
+        assert_eq!(dropped, 2);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, "thanks");
+    }
+}
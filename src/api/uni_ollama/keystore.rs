@@ -0,0 +1,226 @@
+//! An encrypted file of named api_keys, so that they are never stored in plaintext.
+//! The config refers to them as `"keystore:NAME"`
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::{bail, Context};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
+
+/// The file name of the key store, next to `config.json`
+pub const KEY_STORE_FILE: &str = "keys.enc";
+/// The environment variable holding the passphrase of the key store
+pub const PASSPHRASE_ENV: &str = "UNI_LLM_KEYS_PASSPHRASE";
+/// The environment variable holding the path of a file used as the passphrase
+pub const KEY_FILE_ENV: &str = "UNI_LLM_KEYS_KEY_FILE";
+
+const VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// The key store as written to the file, see [`KeyStore`]
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedStore {
+    version: u32,
+    /// Of the argon2id key derivation
+    #[serde_as(as = "Base64")]
+    salt: Vec<u8>,
+    #[serde_as(as = "Base64")]
+    nonce: Vec<u8>,
+    /// The entries as JSON, encrypted with XChaCha20-Poly1305
+    #[serde_as(as = "Base64")]
+    ciphertext: Vec<u8>,
+}
+
+fn cipher(passphrase: &[u8], salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Derive the key of the key store: {e}"))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// An encrypted file of named api_keys, unlocked by a passphrase or a key file.
+/// The passphrase is taken from `--key-file`, [`KEY_FILE_ENV`] or [`PASSPHRASE_ENV`],
+/// or asked for on the terminal, the first time it is needed only
+pub struct KeyStore {
+    path: PathBuf,
+    key_file: Option<PathBuf>,
+    passphrase: OnceLock<Vec<u8>>,
+    /// Cleared once nobody is at the terminal to answer, see [`Self::stop_prompting`]
+    prompt: AtomicBool,
+}
+
+impl KeyStore {
+    /// The key store at `path`, which may not exist yet
+    pub fn new(path: PathBuf, key_file: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            key_file,
+            passphrase: OnceLock::new(),
+            prompt: AtomicBool::new(true),
+        }
+    }
+
+    /// Never ask for the passphrase on the terminal from now on, but fail as locked.
+    /// The server calls it once started, as reloads run on its worker threads
+    pub(crate) fn stop_prompting(&self) {
+        self.prompt.store(false, Ordering::Relaxed);
+    }
+
+    /// The path of the key store file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the key store file exists
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn passphrase(&self, confirm: bool) -> anyhow::Result<&[u8]> {
+        if let Some(passphrase) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+        let key_file = self
+            .key_file
+            .clone()
+            .or_else(|| std::env::var_os(KEY_FILE_ENV).map(PathBuf::from));
+        let passphrase = if let Some(key_file) = key_file {
+            let mut passphrase = std::fs::read(&key_file)
+                .with_context(|| format!("Read the key file {key_file:?}"))?;
+            // Files written by editors and `echo` end with a newline
+            while passphrase
+                .last()
+                .is_some_and(|b| matches!(b, b'\r' | b'\n'))
+            {
+                passphrase.pop();
+            }
+            passphrase
+        } else if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+            passphrase.into_bytes()
+        } else if self.prompt.load(Ordering::Relaxed) && std::io::stdin().is_terminal() {
+            let passphrase = rpassword::prompt_password("Passphrase of the key store: ")?;
+            if confirm
+                && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase
+            {
+                bail!("The passphrases differ");
+            }
+            passphrase.into_bytes()
+        } else {
+            bail!(
+                "The key store {:?} is locked, set {KEY_FILE_ENV} or {PASSPHRASE_ENV}",
+                self.path
+            );
+        };
+        if passphrase.is_empty() {
+            bail!("The passphrase of the key store is empty");
+        }
+        Ok(self.passphrase.get_or_init(|| passphrase))
+    }
+
+    /// Decrypt every entry, an empty store if the file does not exist yet
+    pub fn load(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        if !self.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let content = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Read {:?}", self.path))?;
+        let sealed: SealedStore = serde_json::from_str(&content)
+            .with_context(|| format!("Parse {:?}", self.path))?;
+        if sealed.version != VERSION {
+            bail!("Unsupported version {} of {:?}", sealed.version, self.path);
+        }
+        if sealed.nonce.len() != 24 {
+            bail!("Invalid nonce in {:?}", self.path);
+        }
+        let plaintext = cipher(self.passphrase(false)?, &sealed.salt)?
+            .decrypt(
+                XNonce::from_slice(&sealed.nonce),
+                sealed.ciphertext.as_ref(),
+            )
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase for {:?}", self.path))?;
+        serde_json::from_slice(&plaintext)
+            .with_context(|| format!("Parse {:?}", self.path))
+    }
+
+    /// Encrypt `entries` with a fresh salt and nonce, replacing the file
+    pub fn save(&self, entries: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher(self.passphrase(!self.exists())?, &salt)?
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                serde_json::to_vec(entries)?.as_ref(),
+            )
+            .map_err(|_| anyhow::anyhow!("Encrypt the key store"))?;
+        let sealed = SealedStore {
+            version: VERSION,
+            salt,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file =
+            std::fs::File::create(&tmp).with_context(|| format!("Write {tmp:?}"))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(&serde_json::to_vec_pretty(&sealed)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Write {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::KeyStore;
+
+    #[test]
+    fn test_key_store() {
+        let dir = std::env::temp_dir().join("uni-llm-test-keystore");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.enc");
+        let _ = std::fs::remove_file(&path);
+        let key_file = dir.join("key");
+        std::fs::write(&key_file, "correct horse").unwrap();
+
+        let store = KeyStore::new(path.clone(), Some(key_file.clone()));
+        assert!(store.load().unwrap().is_empty());
+        let entries = BTreeMap::from([("deepseek".to_string(), "sk-secret".to_string())]);
+        store.save(&entries).unwrap();
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("sk-secret"));
+        // The newline at the end of the key file is not part of the passphrase
+        std::fs::write(&key_file, "correct horse\r\n").unwrap();
+        let reopened = KeyStore::new(path.clone(), Some(key_file.clone()));
+        assert_eq!(reopened.load().unwrap(), entries);
+
+        std::fs::write(&key_file, "wrong horse").unwrap();
+        let wrong = KeyStore::new(path, Some(key_file));
+        assert!(
+            format!("{:#}", wrong.load().unwrap_err()).starts_with("Wrong passphrase")
+        );
+    }
+}
@@ -137,7 +137,7 @@ pub(crate) struct RespMessage {
     pub images: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub(crate) struct OllamaChatRequest {
     pub model: String,
     pub messages: Vec<ReqMessage>,
@@ -156,7 +156,7 @@ pub(crate) struct OllamaChatRequest {
     pub keep_alive: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct ReqMessage {
     pub role: Role,
     pub content: String,
@@ -181,7 +181,7 @@ impl Default for Role {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct ToolCall {
     pub id: String,
     #[serde(rename = "type")]
@@ -189,20 +189,20 @@ pub(crate) struct ToolCall {
     pub function: FunctionCall,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct FunctionCall {
     pub name: String,
     pub arguments: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct Tool {
     #[serde(rename = "type")]
     pub type_: String,
     pub function: ToolFunction,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct ToolFunction {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1,5 +1,22 @@
+pub(crate) mod admin;
+pub(crate) mod balance;
+pub(crate) mod cache;
 pub(crate) mod chat;
+pub(crate) mod coalesce;
 pub(crate) mod config;
+pub(crate) mod continuation;
 pub(crate) mod error;
+pub(crate) mod format;
+pub(crate) mod gate;
+pub(crate) mod health;
+pub(crate) mod hedge;
+pub(crate) mod history;
+pub(crate) mod keystore;
 pub(crate) mod message;
+pub(crate) mod normalize;
+pub(crate) mod reload;
+pub(crate) mod secret;
+pub(crate) mod summarize;
 pub(crate) mod tag;
+pub(crate) mod timeout;
+pub(crate) mod tokenize;
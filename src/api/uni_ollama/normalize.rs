@@ -0,0 +1,268 @@
+//! Normalize the messages sent by the client before they reach a provider
+use super::{
+    config::{ApiKeyProvider, ModelInfo},
+    message::{ReqMessage, Role},
+};
+
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+/// Content of the user turn inserted before a leading assistant message
+const USER_PLACEHOLDER: &str = "Continue.";
+
+/// The role ordering rules of a provider
+struct RoleRules {
+    /// Adjacent messages of the same role are rejected
+    merge_adjacent: bool,
+    /// System messages are sent separately, so the turns around them are adjacent as well
+    system_out_of_band: bool,
+    /// The first non-system message must come from the user
+    leading_user: bool,
+    /// `tool` messages and tool calls are understood
+    tools: bool,
+}
+
+impl RoleRules {
+    fn new(provider: &ApiKeyProvider, supports_tools: Option<bool>) -> Self {
+        match provider {
+            // Gemini requires alternating user/model turns
+            ApiKeyProvider::Google => Self {
+                merge_adjacent: true,
+                system_out_of_band: true,
+                leading_user: true,
+                tools: supports_tools.unwrap_or(false),
+            },
+            ApiKeyProvider::Aliyun
+            | ApiKeyProvider::Tencent
+            | ApiKeyProvider::Bytedance
+            | ApiKeyProvider::DeepSeek
+            | ApiKeyProvider::Siliconflow => Self {
+                merge_adjacent: true,
+                system_out_of_band: false,
+                leading_user: true,
+                tools: supports_tools.unwrap_or(true),
+            },
+            // Nothing is known about a custom provider, so leave the order as it is
+            ApiKeyProvider::Custom(_) => Self {
+                merge_adjacent: false,
+                system_out_of_band: false,
+                leading_user: false,
+                tools: supports_tools.unwrap_or(true),
+            },
+        }
+    }
+}
+
+fn has_tool_calls(msg: &ReqMessage) -> bool {
+    msg.tool_calls
+        .as_ref()
+        .is_some_and(|calls| !calls.is_empty())
+}
+
+fn is_empty(msg: &ReqMessage) -> bool {
+    msg.content.trim().is_empty()
+        && !has_tool_calls(msg)
+        && msg.images.as_ref().map_or(true, |images| images.is_empty())
+}
+
+/// Tool results and tool calls have to stay separate messages
+fn is_mergeable(msg: &ReqMessage) -> bool {
+    msg.role != Role::Tool && !has_tool_calls(msg)
+}
+
+/// Turn tool results into user messages and tool calls into text
+fn convert_tool_messages(model_id: &str, index: usize, msg: &mut ReqMessage) {
+    if msg.role == Role::Tool {
+        tracing::info!("Normalize {model_id}: converted tool message {index} to user");
+        msg.role = Role::User;
+        msg.content = format!("Tool result:\n{}", msg.content);
+    }
+    if let Some(calls) = msg.tool_calls.take().filter(|calls| !calls.is_empty()) {
+        tracing::info!(
+            "Normalize {model_id}: converted tool calls of message {index} to text"
+        );
+        for call in calls {
+            msg.content.push_str(&format!(
+                "\nTool call: {}({})",
+                call.function.name, call.function.arguments
+            ));
+        }
+    }
+}
+
+/// Rewrite `messages` to satisfy the role ordering rules of `provider`:
+/// convert tool messages the model does not support, drop empty messages,
+/// merge adjacent messages of the same role and insert a leading user turn
+pub(crate) fn normalize_messages(
+    model_id: &str,
+    provider: &ApiKeyProvider,
+    supports_tools: Option<bool>,
+    messages: &mut Vec<ReqMessage>,
+) {
+    let rules = RoleRules::new(provider, supports_tools);
+    let mut normalized: Vec<ReqMessage> = Vec::with_capacity(messages.len());
+    for (i, mut msg) in std::mem::take(messages).into_iter().enumerate() {
+        if !rules.tools {
+            convert_tool_messages(model_id, i, &mut msg);
+        }
+        if is_empty(&msg) {
+            tracing::info!(
+                "Normalize {model_id}: dropped empty {:?} message {i}",
+                msg.role
+            );
+            continue;
+        }
+        if rules.merge_adjacent && is_mergeable(&msg) {
+            let prev = if rules.system_out_of_band && msg.role != Role::System {
+                normalized.iter_mut().rev().find(|m| m.role != Role::System)
+            } else {
+                normalized.last_mut()
+            };
+            if let Some(prev) = prev.filter(|m| m.role == msg.role && is_mergeable(m)) {
+                tracing::info!(
+                    "Normalize {model_id}: merged {:?} message {i} into the previous one",
+                    msg.role
+                );
+                prev.content.push_str("\n\n");
+                prev.content.push_str(&msg.content);
+                if let Some(images) = msg.images {
+                    prev.images.get_or_insert_with(Vec::new).extend(images);
+                }
+                continue;
+            }
+        }
+        normalized.push(msg);
+    }
+    if rules.leading_user {
+        let first = normalized.iter().position(|m| m.role != Role::System);
+        if let Some(first) = first.filter(|i| normalized[*i].role != Role::User) {
+            tracing::info!(
+                "Normalize {model_id}: inserted a user turn before the leading {:?} message",
+                normalized[first].role
+            );
+            normalized.insert(
+                first,
+                ReqMessage {
+                    role: Role::User,
+                    content: USER_PLACEHOLDER.to_string(),
+                    images: None,
+                    tool_calls: None,
+                },
+            );
+        }
+    }
+    *messages = normalized;
+}
+
+/// Remove the `<think>...</think>` blocks from `content`,
+/// an unclosed block is a reasoning that was cut off and runs to the end.
+///
+/// Returns `None` if there is nothing to remove.
+fn strip_reasoning(content: &str) -> Option<String> {
+    let mut start = content.find(THINK_START)?;
+    let mut stripped = String::with_capacity(content.len());
+    let mut rest = content;
+    loop {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start + THINK_START.len()..];
+        match rest.find(THINK_END) {
+            Some(end) => rest = &rest[end + THINK_END.len()..],
+            None => rest = "",
+        }
+        match rest.find(THINK_START) {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    stripped.push_str(rest);
+    Some(stripped.trim_start().to_string())
+}
+
+/// Remove the reasoning of earlier assistant turns, which uni-llm puts into the content
+/// and clients send back, if [`ModelInfo::strip_reasoning`] is enabled.
+/// Returns whether any message changed
+pub(crate) fn strip_history_reasoning(
+    model_id: &str,
+    model_info: &ModelInfo,
+    messages: &mut [ReqMessage],
+) -> bool {
+    if !model_info.strip_reasoning {
+        return false;
+    }
+    let mut changed = false;
+    for (i, msg) in messages.iter_mut().enumerate() {
+        if msg.role != Role::Assistant {
+            continue;
+        }
+        if let Some(stripped) = strip_reasoning(&msg.content) {
+            tracing::debug!(
+                "Stripped reasoning of message {i} for {model_id}: {} -> {} bytes",
+                msg.content.len(),
+                stripped.len()
+            );
+            msg.content = stripped;
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::uni_ollama::{
+        config::ApiKeyProvider,
+        message::{ReqMessage, Role},
+    };
+
+    use super::{normalize_messages, strip_reasoning};
+
+    fn msg(role: Role, content: &str) -> ReqMessage {
+        ReqMessage {
+            role,
+            content: content.to_string(),
+            images: None,
+            tool_calls: None,
+        }
+    }
+
+    #[test]
+    fn test_normalize_gemini() {
+        let mut messages = vec![
+            msg(Role::System, "be brief"),
+            msg(Role::Assistant, "hello"),
+            msg(Role::User, "a"),
+            msg(Role::System, "use english"),
+            msg(Role::User, "b"),
+            msg(Role::Assistant, " "),
+            msg(Role::Tool, "42"),
+        ];
+        normalize_messages("gemini", &ApiKeyProvider::Google, None, &mut messages);
+        let turns = messages
+            .iter()
+            .map(|m| (m.role, m.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            turns,
+            [
+                (Role::System, "be brief"),
+                (Role::User, "Continue."),
+                (Role::Assistant, "hello"),
+                (Role::User, "a\n\nb\n\nTool result:\n42"),
+                (Role::System, "use english"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strip_reasoning() {
+        assert_eq!(strip_reasoning("no reasoning"), None);
+        assert_eq!(
+            strip_reasoning("<think>\nhmm\n</think>\n\nanswer").as_deref(),
+            Some("answer")
+        );
+        assert_eq!(
+            strip_reasoning("a<think>x</think> b<think>y</think>c").as_deref(),
+            Some("a bc")
+        );
+        assert_eq!(strip_reasoning("a<think>cut off").as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,320 @@
+//! Reload the config file while serving, on SIGHUP or when the file changes
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use notify::{RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{SharedState, SharedStateRef};
+
+use super::{
+    config::UniModelsInfo,
+    format::ConfigFormat,
+    keystore::{KeyStore, KEY_STORE_FILE},
+};
+
+/// Swap in `new`, a [resolved](UniModelsInfo::resolve) config,
+/// keeping the runtime state of the keys that still exist
+pub(crate) fn apply(state: &SharedState, mut new: UniModelsInfo) {
+    let mut guard = state.model_config.write();
+    let changes = diff(&guard, &new);
+    if changes.is_empty() {
+        tracing::info!("Config reloaded without changes");
+        return;
+    }
+    for (api_key_id, info) in new.api_keys.iter_mut() {
+        if let Some(old) = guard.api_keys.get(api_key_id) {
+            info.keep_state(old);
+        }
+    }
+    state.clients.reconfigure(&new);
+    state.cache.reconfigure(new.cache.as_ref());
+    *guard = new;
+    tracing::info!("Config reloaded: {}", changes.join(", "));
+}
+
+/// What changed from `old` to `new`, without revealing any secret
+fn diff(old: &UniModelsInfo, new: &UniModelsInfo) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff_entries("api_key", &old.api_keys, &new.api_keys, &mut changes);
+    diff_entries("model", &old.models, &new.models, &mut changes);
+    if old.proxy_url != new.proxy_url {
+        changes.push("changed proxy_url".to_string());
+    }
+    if old.no_proxy != new.no_proxy {
+        changes.push("changed no_proxy".to_string());
+    }
+    if old.rate_limits != new.rate_limits {
+        changes.push("changed rate_limits".to_string());
+    }
+    if old.cache != new.cache {
+        changes.push("changed cache".to_string());
+    }
+    if old.admin != new.admin {
+        changes.push("changed admin".to_string());
+    }
+    changes
+}
+
+fn diff_entries<T: Serialize>(
+    kind: &str,
+    old: &HashMap<String, T>,
+    new: &HashMap<String, T>,
+    changes: &mut Vec<String>,
+) {
+    let mut ids = old.keys().chain(new.keys()).collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        match (old.get(id), new.get(id)) {
+            (None, Some(_)) => changes.push(format!("added {kind} {id}")),
+            (Some(_), None) => changes.push(format!("removed {kind} {id}")),
+            (Some(old), Some(new))
+                if serde_json::to_value(old).ok() != serde_json::to_value(new).ok() =>
+            {
+                changes.push(format!("changed {kind} {id}"))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Where the served config comes from, see [`update`]
+pub(crate) struct ConfigFile {
+    /// Changes are kept in memory only without a file
+    path: Option<PathBuf>,
+    /// The config as in the file, with its secret references unresolved.
+    /// Its lock serializes the updates
+    raw: Mutex<UniModelsInfo>,
+    /// Where `"keystore:NAME"` references are looked up
+    key_store: Option<KeyStore>,
+}
+
+impl ConfigFile {
+    pub(crate) fn new(
+        path: Option<PathBuf>,
+        raw: UniModelsInfo,
+        key_store: Option<KeyStore>,
+    ) -> Self {
+        Self {
+            path,
+            raw: Mutex::new(raw),
+            key_store,
+        }
+    }
+
+    /// [Resolve](UniModelsInfo::resolve) `raw` with the key store of this file
+    pub(crate) fn resolve(&self, raw: &UniModelsInfo) -> anyhow::Result<UniModelsInfo> {
+        raw.resolve(self.key_store.as_ref())
+    }
+
+    /// Fail rather than ask for the passphrase of the key store from now on,
+    /// see [`KeyStore::stop_prompting`]
+    pub(crate) fn stop_prompting(&self) {
+        if let Some(key_store) = &self.key_store {
+            key_store.stop_prompting();
+        }
+    }
+
+    /// Look into the config as in the file, where secrets may still be references
+    pub(crate) fn read<T>(&self, f: impl FnOnce(&UniModelsInfo) -> T) -> T {
+        f(&self.raw.lock())
+    }
+}
+
+/// Change the config with `f`, then persist it to the [`ConfigFile`] and apply it.
+/// `f` sees the config as in the file, so that resolved secrets are never written back.
+/// Runs on a blocking thread, as the file is written and synced under the lock
+pub(crate) async fn update<T: Send + 'static>(
+    state: &SharedStateRef,
+    f: impl FnOnce(&mut UniModelsInfo) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || update_blocking(&state, f)).await?
+}
+
+fn update_blocking<T>(
+    state: &SharedState,
+    f: impl FnOnce(&mut UniModelsInfo) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut raw = state.config_file.raw.lock();
+    let mut new = raw.clone();
+    let output = f(&mut new)?;
+    let resolved = state.config_file.resolve(&new)?;
+    if let Some(path) = &state.config_file.path {
+        let format = ConfigFormat::of(path)?;
+        // Keeps what it can of the file as it is written
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => format.rewrite(&content, &*raw, &new)?,
+            Err(_) => format.to_string(&new)?,
+        };
+        write_atomically(path, content.as_bytes())
+            .with_context(|| format!("Write {path:?}"))?;
+    }
+    apply(state, resolved);
+    *raw = new;
+    Ok(output)
+}
+
+/// Write `content` to a temporary file next to `path` and rename it over `path`,
+/// so that `path` is never seen half written
+fn write_atomically(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+fn reload(state: &SharedState, path: &Path) -> anyhow::Result<()> {
+    // Read under the lock, or an update written meanwhile would be undone by the older file
+    let mut raw = state.config_file.raw.lock();
+    let new = UniModelsInfo::read(path)?;
+    let resolved = state.config_file.resolve(&new)?;
+    apply(state, resolved);
+    *raw = new;
+    Ok(())
+}
+
+/// Reload the config from `path` on SIGHUP and whenever the file or the key store next to it changes.
+/// The secret files it refers to are not watched, send SIGHUP after changing them
+pub(crate) fn watch(state: SharedStateRef, path: PathBuf) -> anyhow::Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<&'static str>();
+    let file_names = [
+        path.file_name().context("Invalid config path")?.to_owned(),
+        KEY_STORE_FILE.into(),
+    ];
+    let file_tx = tx.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event)
+                    if !event.kind.is_access()
+                        && event
+                            .paths
+                            .iter()
+                            .filter_map(|path| path.file_name())
+                            .any(|name| file_names.iter().any(|n| n == name)) =>
+                {
+                    let _ = file_tx.send("a change of the file");
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Failed to watch the config: {e}"),
+            }
+        })?;
+    // Editors often replace the file rather than write it, so watch its directory
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Watch {dir:?}"))?;
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup()).context("Listen for SIGHUP")?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if tx.send("SIGHUP").is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    tokio::spawn(async move {
+        // Dropping the watcher stops watching
+        let _watcher = watcher;
+        while let Some(reason) = rx.recv().await {
+            // A save often takes several events, reload once they settle
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            tracing::info!("Reload {path:?} after {reason}");
+            if let Err(e) = reload(&state, &path) {
+                tracing::error!(
+                    "Keep the current config, failed to reload {path:?}: {e:#}"
+                );
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Instant};
+
+    use axum::http::StatusCode;
+
+    use crate::api::{
+        provider::error::UpstreamError,
+        uni_ollama::config::{ApiKeyInfo, UniModelsInfo},
+    };
+
+    use super::diff;
+
+    #[test]
+    fn test_reload_keeps_state() {
+        let mut old = ApiKeyInfo {
+            api_key: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            ..Default::default()
+        };
+        // Take `a` and `b`, which leaves `c` next
+        old.selected().unwrap();
+        old.selected().unwrap();
+        let mut new = ApiKeyInfo {
+            api_key: vec!["c".to_string(), "d".to_string(), "a".to_string()],
+            ..Default::default()
+        };
+        new.keep_state(&old);
+        assert_eq!(new.selected().unwrap().api_key, "c");
+
+        // Once `c` is gone, the first key after it that is kept goes next
+        let mut new = ApiKeyInfo {
+            api_key: vec!["b".to_string(), "a".to_string()],
+            ..Default::default()
+        };
+        new.keep_state(&old);
+        assert_eq!(new.selected().unwrap().api_key, "a");
+
+        // A disabled key stays disabled, unless its value changes
+        let unauthorized = UpstreamError::Status {
+            status: StatusCode::UNAUTHORIZED,
+            retry_after: None,
+            body: String::new(),
+        };
+        old.record(0, Err(&unauthorized.into()));
+        let mut reloaded = ApiKeyInfo {
+            api_key: vec!["d".to_string(), "a".to_string()],
+            ..Default::default()
+        };
+        reloaded.keep_state(&old);
+        let now = Instant::now();
+        assert!(reloaded.is_usable(0, now));
+        assert!(!reloaded.is_usable(1, now));
+
+        let mut old_config = UniModelsInfo {
+            api_keys: HashMap::from([("x".to_string(), old)]),
+            ..Default::default()
+        };
+        old_config.models.clear();
+        let mut new_config = UniModelsInfo {
+            api_keys: HashMap::from([("y".to_string(), new)]),
+            proxy_url: None,
+            ..Default::default()
+        };
+        new_config.models.clear();
+        assert_eq!(
+            diff(&old_config, &new_config),
+            ["removed api_key x", "added api_key y", "changed proxy_url"]
+        );
+    }
+}
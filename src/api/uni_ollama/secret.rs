@@ -0,0 +1,152 @@
+//! Resolve the secret references in the config, so that the file itself holds no secret:
+//! `"env:NAME"`, `"file:PATH"` and `"keystore:NAME"` stand for a whole value,
+//! and `${NAME}` is replaced by an environment variable anywhere in a string
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::Value;
+
+use super::keystore::KeyStore;
+
+const ENV_PREFIX: &str = "env:";
+const FILE_PREFIX: &str = "file:";
+const KEY_STORE_PREFIX: &str = "keystore:";
+
+/// Whether `s` refers to a secret rather than being one
+pub(crate) fn is_reference(s: &str) -> bool {
+    s.starts_with(ENV_PREFIX)
+        || s.starts_with(FILE_PREFIX)
+        || s.starts_with(KEY_STORE_PREFIX)
+        || s.contains("${")
+}
+
+/// Resolves the references of one config, decrypting the [`KeyStore`] once at most
+pub(crate) struct Resolver<'a> {
+    key_store: Option<&'a KeyStore>,
+    keys: Option<BTreeMap<String, String>>,
+}
+
+impl<'a> Resolver<'a> {
+    pub(crate) fn new(key_store: Option<&'a KeyStore>) -> Self {
+        Self {
+            key_store,
+            keys: None,
+        }
+    }
+
+    fn key(&mut self, name: &str) -> anyhow::Result<String> {
+        let keys = match &mut self.keys {
+            Some(keys) => keys,
+            keys => {
+                let key_store = self.key_store.context("No key store is available")?;
+                keys.insert(key_store.load()?)
+            }
+        };
+        keys.get(name)
+            .cloned()
+            .with_context(|| format!("key {name} is not in the key store"))
+    }
+
+    /// Replace every reference in the strings of `value`,
+    /// failing with the path of the first one that cannot be resolved
+    pub(crate) fn resolve(
+        &mut self,
+        value: &mut Value,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        match value {
+            Value::String(s) if is_reference(s) => {
+                let resolved = match s.strip_prefix(KEY_STORE_PREFIX) {
+                    Some(name) => self.key(name),
+                    None => resolve_str(s),
+                };
+                *s = resolved.with_context(|| format!("Resolve {path}"))?;
+            }
+            Value::Array(values) => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    self.resolve(value, &format!("{path}[{i}]"))?;
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    let path = match path.is_empty() {
+                        true => key.clone(),
+                        false => format!("{path}.{key}"),
+                    };
+                    self.resolve(value, &path)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn env(name: &str) -> anyhow::Result<String> {
+    std::env::var(name).map_err(|e| anyhow!("environment variable {name}: {e}"))
+}
+
+/// The value `s` refers to, or `s` itself
+fn resolve_str(s: &str) -> anyhow::Result<String> {
+    if let Some(name) = s.strip_prefix(ENV_PREFIX) {
+        return env(name);
+    }
+    if let Some(path) = s.strip_prefix(FILE_PREFIX) {
+        let path = shellexpand::tilde(path);
+        let content = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("secret file {path}"))?;
+        // Files written by editors and `echo` end with a newline
+        return Ok(content.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let mut resolved = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        resolved.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            bail!("unclosed ${{ in {s:?}");
+        };
+        resolved.push_str(&env(&rest[start + 2..start + 2 + len])?);
+        rest = &rest[start + 2 + len + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Resolver;
+
+    #[test]
+    fn test_resolve_secrets() {
+        let dir = std::env::temp_dir().join("uni-llm-test-secret");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("key");
+        std::fs::write(&file, "sk-from-file\n").unwrap();
+        let home = std::env::var("HOME").unwrap();
+
+        let mut value = json!({
+            "api_keys": {
+                "a": { "api_key": ["env:HOME", format!("file:{}", file.display())] },
+                "b": { "provider": { "Custom": "https://${HOME}/v1" } }
+            },
+            "plain": "sk-literal",
+        });
+        Resolver::new(None).resolve(&mut value, "").unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "api_keys": {
+                    "a": { "api_key": [home, "sk-from-file"] },
+                    "b": { "provider": { "Custom": format!("https://{home}/v1") } }
+                },
+                "plain": "sk-literal",
+            })
+        );
+
+        let mut value = json!({ "proxy_url": "http://${UNI_LLM_TEST_UNSET}:1080" });
+        let err = Resolver::new(None).resolve(&mut value, "").unwrap_err();
+        assert!(format!("{err:#}").starts_with("Resolve proxy_url: environment variable"));
+    }
+}
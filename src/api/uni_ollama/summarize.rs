@@ -0,0 +1,439 @@
+//! Compress the older turns of a conversation into a summary written by another model
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use serde::Deserialize;
+
+use crate::{
+    common::tokenizer::{
+        count_prompt_tokens, tokenizer_or_heuristic, Tokenizer, UsageEstimator,
+    },
+    SharedStateRef,
+};
+
+use super::{
+    chat::dispatch,
+    config::SummarizeConfig,
+    history::droppable_units,
+    message::{OllamaChatRequest, ReqMessage, RespMessage, Role},
+};
+
+/// The maximum number of summaries kept in [`SummaryCache`]
+const MAX_CACHED_SUMMARIES: usize = 1024;
+/// Prefix of the system message that replaces the summarized turns
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n";
+const SUMMARIZER_PROMPT: &str = "Summarize the following conversation between a user and \
+an assistant. Keep the facts, decisions, names, numbers and open questions that later turns \
+may refer to. Reply with the summary only.";
+
+/// Summaries keyed by the hash of the summarized messages,
+/// so a conversation is not summarized again on every turn.
+/// The least recently used summary is evicted when full
+#[derive(Default)]
+pub(crate) struct SummaryCache {
+    summaries: Mutex<Summaries>,
+}
+
+#[derive(Default)]
+struct Summaries {
+    tick: u64,
+    /// The summaries along with when they were last used
+    entries: HashMap<u64, (String, u64)>,
+    /// The hashes by when they were last used
+    order: BTreeMap<u64, u64>,
+}
+
+impl SummaryCache {
+    fn get(&self, hash: u64) -> Option<String> {
+        let mut summaries = self.summaries.lock();
+        let Summaries {
+            tick,
+            entries,
+            order,
+        } = &mut *summaries;
+        let (summary, used) = entries.get_mut(&hash)?;
+        order.remove(used);
+        *tick += 1;
+        *used = *tick;
+        order.insert(*tick, hash);
+        Some(summary.clone())
+    }
+
+    fn insert(&self, hash: u64, summary: String) {
+        let mut summaries = self.summaries.lock();
+        let Summaries {
+            tick,
+            entries,
+            order,
+        } = &mut *summaries;
+        *tick += 1;
+        if let Some((_, used)) = entries.insert(hash, (summary, *tick)) {
+            order.remove(&used);
+        }
+        order.insert(*tick, hash);
+        while entries.len() > MAX_CACHED_SUMMARIES {
+            let Some((_, oldest)) = order.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+    }
+}
+
+/// The hashes of every prefix of `messages`, summaries of different models never collide
+fn prefix_hashes(summarizer: &str, messages: &[&ReqMessage]) -> Vec<u64> {
+    let mut hasher = DefaultHasher::new();
+    summarizer.hash(&mut hasher);
+    messages
+        .iter()
+        .map(|msg| {
+            (msg.role as u8).hash(&mut hasher);
+            msg.content.hash(&mut hasher);
+            for call in msg.tool_calls.iter().flatten() {
+                call.function.name.hash(&mut hasher);
+                call.function.arguments.to_string().hash(&mut hasher);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Replace the older turns of `messages` with a summary,
+/// reusing a cached summary of an earlier prefix while the result stays below `threshold`.
+///
+/// Returns the number of summarized messages.
+pub(crate) async fn summarize_history(
+    state: &SharedStateRef,
+    config: &SummarizeConfig,
+    tokenizer: &dyn Tokenizer,
+    threshold: usize,
+    messages: &mut Vec<ReqMessage>,
+) -> anyhow::Result<usize> {
+    // Never split the latest turns or a tool call from its results
+    let units = droppable_units(messages);
+    let mut boundary = units.last().map_or(messages.len(), |unit| unit[0]);
+    for unit in units.iter().rev().skip(1) {
+        if messages.len() - unit[0] > config.keep_recent {
+            break;
+        }
+        boundary = unit[0];
+    }
+    let older = messages[..boundary]
+        .iter()
+        .filter(|msg| msg.role != Role::System)
+        .collect::<Vec<_>>();
+    if older.is_empty() {
+        return Ok(0);
+    }
+    let hashes = prefix_hashes(&config.summarizer, &older);
+
+    // The longest prefix that was summarized before
+    let cached = hashes
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, hash)| Some((i + 1, state.summaries.get(*hash)?)));
+    let summarized = match cached {
+        Some((len, summary))
+            if len == older.len()
+                || replaced_tokens(tokenizer, messages, boundary, &summary, len)
+                    <= threshold =>
+        {
+            tracing::info!("Reuse the cached summary of {len} messages");
+            (len, summary)
+        }
+        _ => {
+            let (previous, rest) = match &cached {
+                Some((len, summary)) => (Some(summary.as_str()), &older[*len..]),
+                None => (None, &older[..]),
+            };
+            let summary = request_summary(state, config, previous, rest).await?;
+            state
+                .summaries
+                .insert(*hashes.last().expect("older is not empty"), summary.clone());
+            (older.len(), summary)
+        }
+    };
+    replace_with_summary(messages, boundary, &summarized.1, summarized.0);
+    Ok(summarized.0)
+}
+
+/// Replace the first `len` non-system messages before `boundary` with `summary`
+fn replace_with_summary(
+    messages: &mut Vec<ReqMessage>,
+    boundary: usize,
+    summary: &str,
+    len: usize,
+) {
+    let mut remaining = len;
+    let mut index = 0;
+    messages.retain(|msg| {
+        index += 1;
+        if index > boundary || remaining == 0 || msg.role == Role::System {
+            return true;
+        }
+        remaining -= 1;
+        false
+    });
+    let insert_at = messages
+        .iter()
+        .position(|msg| msg.role != Role::System)
+        .unwrap_or(messages.len());
+    messages.insert(
+        insert_at,
+        ReqMessage {
+            role: Role::System,
+            content: format!("{SUMMARY_PREFIX}{summary}"),
+            images: None,
+            tool_calls: None,
+        },
+    );
+}
+
+/// The prompt tokens after replacing `len` messages with `summary`
+fn replaced_tokens(
+    tokenizer: &dyn Tokenizer,
+    messages: &[ReqMessage],
+    boundary: usize,
+    summary: &str,
+    len: usize,
+) -> usize {
+    let mut messages = messages.to_vec();
+    replace_with_summary(&mut messages, boundary, summary, len);
+    count_prompt_tokens(tokenizer, &messages)
+}
+
+#[derive(Deserialize)]
+struct SummaryResponse {
+    message: RespMessage,
+}
+
+/// Ask the summarizer model to summarize `messages`, continuing from a `previous` summary
+async fn request_summary(
+    state: &SharedStateRef,
+    config: &SummarizeConfig,
+    previous: Option<&str>,
+    messages: &[&ReqMessage],
+) -> anyhow::Result<String> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(SUMMARY_PREFIX);
+        transcript.push_str(previous);
+        transcript.push_str("\n\n");
+    }
+    for msg in messages {
+        let role = serde_json::to_value(msg.role)?;
+        transcript.push_str(role.as_str().unwrap_or_default());
+        transcript.push_str(": ");
+        transcript.push_str(&msg.content);
+        transcript.push('\n');
+        // Later turns may refer to what the tools were asked
+        for call in msg.tool_calls.iter().flatten() {
+            transcript.push_str(&format!(
+                "(called tool {} with {})\n",
+                call.function.name, call.function.arguments
+            ));
+        }
+    }
+    let payload = OllamaChatRequest {
+        model: config.summarizer.clone(),
+        messages: vec![
+            ReqMessage {
+                role: Role::System,
+                content: SUMMARIZER_PROMPT.to_string(),
+                images: None,
+                tool_calls: None,
+            },
+            ReqMessage {
+                role: Role::User,
+                content: transcript,
+                images: None,
+                tool_calls: None,
+            },
+        ],
+        tools: Vec::new(),
+        format: None,
+        options: None,
+        stream: false,
+        keep_alive: String::new(),
+    };
+    let (model_info, tokenizer_kind) = {
+        let guard = state.model_config.read();
+        let model_info = guard
+            .models
+            .get(&config.summarizer)
+            .context("Invalid summarizer model id")?
+            .clone();
+        let tokenizer_kind = guard.tokenizer_kind(&model_info);
+        (model_info, tokenizer_kind)
+    };
+    let tokenizer = tokenizer_or_heuristic(&tokenizer_kind).await;
+    let prompt_tokens = count_prompt_tokens(tokenizer.as_ref(), &payload.messages);
+    let estimator = UsageEstimator::new(tokenizer, prompt_tokens as u32);
+    tracing::info!(
+        "Summarize {} messages with {}",
+        messages.len(),
+        config.summarizer
+    );
+    let res = dispatch(state, payload, &model_info, estimator, None).await?;
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+    let content = serde_json::from_slice::<SummaryResponse>(&body)
+        .context("Parse summary response")?
+        .message
+        .content;
+    // Reasoning models put their thoughts before the summary
+    let summary = content
+        .rsplit_once("</think>")
+        .map_or(content.as_str(), |(_, summary)| summary);
+    Ok(summary.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::StatusCode;
+
+    use crate::{
+        api::{
+            mock::{self, MockProvider},
+            uni_ollama::{
+                config::{HistoryPolicy, ModelInfo, SummarizeConfig, TokenizerKind},
+                history::fit_history,
+                message::{FunctionCall, ReqMessage, Role, ToolCall},
+            },
+        },
+        common::tokenizer::get_tokenizer,
+        UniModelsInfo,
+    };
+
+    use super::{SummaryCache, MAX_CACHED_SUMMARIES};
+
+    fn msg(role: Role, content: &str) -> ReqMessage {
+        ReqMessage {
+            role,
+            content: content.to_string(),
+            images: None,
+            tool_calls: None,
+        }
+    }
+
+    fn config(provider: &MockProvider, chat: ModelInfo) -> UniModelsInfo {
+        UniModelsInfo {
+            api_keys: HashMap::from([("mock".to_string(), provider.api_key())]),
+            models: HashMap::from([
+                ("chat".to_string(), chat),
+                ("sum".to_string(), mock::model("s", "mock")),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarize_history() {
+        let tokenizer = get_tokenizer(&TokenizerKind::Heuristic).unwrap();
+        let long = "word ".repeat(100);
+        let mut call = msg(Role::Assistant, "");
+        call.tool_calls = Some(vec![ToolCall {
+            id: "0".to_string(),
+            type_: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: serde_json::json!({ "q": "rust" }),
+            },
+        }]);
+        let conversation = vec![
+            msg(Role::System, "be brief"),
+            msg(Role::User, &long),
+            call,
+            msg(Role::Tool, &long),
+            msg(Role::Assistant, "done"),
+            msg(Role::User, "thanks"),
+        ];
+
+        let provider =
+            MockProvider::start(|_| async { mock::completion("SUMMARY") }).await;
+        let chat = ModelInfo {
+            history_policy: HistoryPolicy::Summarize(SummarizeConfig {
+                summarizer: "sum".to_string(),
+                threshold: Some(50),
+                keep_recent: 1,
+            }),
+            ..mock::model("m", "mock")
+        };
+        let state = mock::test_state(config(&provider, chat.clone()));
+        for _ in 0..2 {
+            let mut messages = conversation.clone();
+            let fitted = fit_history(
+                &state,
+                "chat",
+                &chat,
+                tokenizer.as_ref(),
+                &mut messages,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(fitted.summarized, Some(4));
+            let contents = messages
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                contents,
+                [
+                    "be brief",
+                    "Summary of the earlier conversation:\nSUMMARY",
+                    "thanks"
+                ]
+            );
+        }
+        // The second time is answered by the cache
+        let requests = provider.requests();
+        assert_eq!(requests.len(), 1);
+        let transcript = requests[0]["messages"][1]["content"].as_str().unwrap();
+        assert!(transcript.contains(r#"(called tool search with {"q":"rust"})"#));
+
+        // Dropping the oldest turns is the fallback when the summarizer fails
+        let provider =
+            MockProvider::start(|_| async { mock::error(StatusCode::BAD_GATEWAY) }).await;
+        let chat = ModelInfo {
+            context_length: Some(50),
+            ..chat
+        };
+        let state = mock::test_state(config(&provider, chat.clone()));
+        let mut messages = conversation.clone();
+        let fitted = fit_history(
+            &state,
+            "chat",
+            &chat,
+            tokenizer.as_ref(),
+            &mut messages,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(provider.requests().len(), 1);
+        assert_eq!((fitted.summarized, fitted.dropped), (Some(0), Some(4)));
+        let roles = messages.iter().map(|m| m.role).collect::<Vec<_>>();
+        assert_eq!(roles, [Role::System, Role::User]);
+    }
+
+    #[test]
+    fn test_summary_cache() {
+        let cache = SummaryCache::default();
+        for hash in 0..MAX_CACHED_SUMMARIES as u64 {
+            cache.insert(hash, hash.to_string());
+        }
+        // Using the oldest one keeps it, the next oldest is evicted instead
+        assert_eq!(cache.get(0).as_deref(), Some("0"));
+        cache.insert(u64::MAX, "new".to_string());
+        assert_eq!(cache.get(0).as_deref(), Some("0"));
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(u64::MAX).as_deref(), Some("new"));
+    }
+}
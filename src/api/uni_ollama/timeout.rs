@@ -0,0 +1,202 @@
+//! Enforce the [`TimeoutConfig`] of a request
+use std::time::Duration;
+
+use axum::{body::Body, response::Response};
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use crate::api::provider::error::{TimeoutKind, UpstreamError};
+
+use super::config::TimeoutConfig;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl TimeoutConfig {
+    /// Fill the unset timeouts from `other`
+    pub(crate) fn or(&self, other: &TimeoutConfig) -> TimeoutConfig {
+        TimeoutConfig {
+            connect_ms: self.connect_ms.or(other.connect_ms),
+            first_byte_ms: self.first_byte_ms.or(other.first_byte_ms),
+            idle_ms: self.idle_ms.or(other.idle_ms),
+            total_ms: self.total_ms.or(other.total_ms),
+        }
+    }
+
+    pub(crate) fn connect(&self) -> Duration {
+        self.connect_ms
+            .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_millis)
+    }
+
+    pub(crate) fn first_byte(&self) -> Duration {
+        self.first_byte_ms
+            .map_or(DEFAULT_FIRST_BYTE_TIMEOUT, Duration::from_millis)
+    }
+
+    fn idle(&self) -> Duration {
+        self.idle_ms
+            .map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_millis)
+    }
+
+    /// The deadline of the whole request, which `started` before any target was tried
+    pub(crate) fn deadline(&self, started: Instant) -> Option<Instant> {
+        self.total_ms
+            .map(|total| started + Duration::from_millis(total))
+    }
+
+    /// The deadline of the first token of an attempt started now,
+    /// with the error to report when it expires
+    pub(crate) fn first_token_deadline(
+        &self,
+        deadline: Option<Instant>,
+    ) -> (Instant, UpstreamError) {
+        let first_byte = Instant::now() + self.first_byte();
+        match (deadline, self.total_ms) {
+            (Some(deadline), Some(total)) if deadline < first_byte => (
+                deadline,
+                UpstreamError::Timeout(TimeoutKind::Total, Duration::from_millis(total)),
+            ),
+            _ => (
+                first_byte,
+                UpstreamError::Timeout(TimeoutKind::FirstByte, self.first_byte()),
+            ),
+        }
+    }
+
+    /// End the body of `res` with a timeout error when no chunk arrives in time,
+    /// or when it is not done by `deadline`
+    pub(crate) fn limit_stream(
+        &self,
+        res: Response,
+        deadline: Option<Instant>,
+    ) -> Response {
+        let idle = self.idle();
+        let total = self.total_ms.map(Duration::from_millis);
+        res.map(|body| {
+            let mut stream = body.into_data_stream();
+            Body::from_stream(async_stream::stream! {
+                loop {
+                    let idle_at = Instant::now() + idle;
+                    let (at, err) = match (deadline, total) {
+                        (Some(deadline), Some(total)) if deadline < idle_at => {
+                            (deadline, UpstreamError::Timeout(TimeoutKind::Total, total))
+                        }
+                        _ => (idle_at, UpstreamError::Timeout(TimeoutKind::Idle, idle)),
+                    };
+                    match tokio::time::timeout_at(at, stream.next()).await {
+                        Ok(Some(chunk)) => yield chunk.map_err(anyhow::Error::from),
+                        Ok(None) => break,
+                        Err(_) => {
+                            tracing::error!("Stream aborted: {err}");
+                            yield Err(anyhow::Error::from(err));
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+    }
+}
+
+/// Tell a connect timeout apart from other request errors
+pub(crate) fn classify_connect_timeout(
+    err: anyhow::Error,
+    connect: Duration,
+) -> anyhow::Error {
+    match UpstreamError::of(&err) {
+        Some(UpstreamError::Request(e)) if e.is_connect() && e.is_timeout() => {
+            UpstreamError::Timeout(TimeoutKind::Connect, connect).into()
+        }
+        _ => err,
+    }
+}
+
+/// Wait for the first chunk of the body of `res`, which is the first token of a streaming response
+pub(crate) async fn first_token(res: Response) -> anyhow::Result<Response> {
+    let (parts, body) = res.into_parts();
+    let mut stream = body.into_data_stream();
+    let first = stream.next().await.transpose()?;
+    let stream = futures::stream::iter(first.map(Ok)).chain(stream);
+    Ok(Response::from_parts(parts, Body::from_stream(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use axum::{body::Body, http::StatusCode, response::Response};
+    use futures::StreamExt;
+
+    use crate::{
+        api::{
+            mock::{self, MockProvider},
+            uni_ollama::{chat::dispatch, config::TimeoutConfig},
+        },
+        ModelInfo, ModelTarget, UniModelsInfo,
+    };
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let stream = futures::stream::once(async { Ok::<_, anyhow::Error>("token") })
+            .chain(futures::stream::pending());
+        let res = Response::new(Body::from_stream(stream));
+        let timeouts = TimeoutConfig {
+            idle_ms: Some(20),
+            ..Default::default()
+        };
+        let mut body = timeouts
+            .limit_stream(res, None)
+            .into_body()
+            .into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "token");
+        let err = body.next().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("idle stream timeout"));
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_total_timeout_covers_failover() {
+        let failing = MockProvider::start(|_| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            mock::error(StatusCode::SERVICE_UNAVAILABLE)
+        })
+        .await;
+        let slow = MockProvider::start(|_| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            mock::stream(&["late"])
+        })
+        .await;
+        let config = UniModelsInfo {
+            api_keys: HashMap::from([
+                ("failing".to_string(), failing.api_key()),
+                ("slow".to_string(), slow.api_key()),
+            ]),
+            ..Default::default()
+        };
+        let state = mock::test_state(config);
+        // Each target alone fits into the total, both together do not
+        let model_info = ModelInfo {
+            fallbacks: vec![ModelTarget {
+                name: "m".to_string(),
+                api_key_id: "slow".to_string(),
+            }],
+            timeouts: Some(TimeoutConfig {
+                total_ms: Some(300),
+                ..Default::default()
+            }),
+            ..mock::model("m", "failing")
+        };
+        let err = dispatch(
+            &state,
+            mock::request("m", true),
+            &model_info,
+            mock::estimator(),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("total timeout"), "{err}");
+        assert_eq!(slow.requests().len(), 1);
+    }
+}
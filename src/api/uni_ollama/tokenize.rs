@@ -0,0 +1,60 @@
+use anyhow::Context;
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::tokenizer::{count_prompt_tokens, tokenizer_or_heuristic},
+    SharedStateRef,
+};
+
+use super::{error::AppError, message::ReqMessage};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TokenizeRequest {
+    model: String,
+    /// A conversation, counted the same way as the prompt of `/api/chat`
+    #[serde(default)]
+    messages: Vec<ReqMessage>,
+    /// A plain text, counted without any message overhead
+    #[serde(default)]
+    prompt: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenizeResponse {
+    model: String,
+    /// The estimated number of tokens
+    tokens: usize,
+    /// See [`crate::ModelInfo::context_length`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context_length: Option<u32>,
+    /// Whether the tokens fit into the context length of the model
+    fits: bool,
+}
+
+/// Estimate the tokens of a prompt with the tokenizer of the model.
+/// This function is called when a POST request is made to `/api/tokenize`.
+pub(crate) async fn api_tokenize(
+    State(state): State<SharedStateRef>,
+    Json(payload): Json<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>, AppError> {
+    let (context_length, tokenizer_kind) = {
+        let guard = state.model_config.read();
+        let model_info = guard
+            .models
+            .get(&payload.model)
+            .context("Invalid model id")?;
+        (model_info.context_length, guard.tokenizer_kind(model_info))
+    };
+    let tokenizer = tokenizer_or_heuristic(&tokenizer_kind).await;
+    let tokens = match payload.prompt {
+        Some(prompt) => tokenizer.count_tokens(&prompt),
+        None => count_prompt_tokens(tokenizer.as_ref(), &payload.messages),
+    };
+    Ok(Json(TokenizeResponse {
+        model: payload.model,
+        tokens,
+        context_length,
+        fits: context_length.map_or(true, |len| tokens <= len as usize),
+    }))
+}
@@ -1,10 +1,17 @@
 //! A binary for the Uni LLM project
-use std::{fs::OpenOptions, path::PathBuf};
+use std::{
+    fs::OpenOptions,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
 use shellexpand::tilde;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-use uni_llm_api::{run_server, UniModelsInfo};
+use uni_llm_api::{
+    run_server_with_reload, ConfigFormat, KeyStore, UniModelsInfo, KEY_STORE_FILE,
+};
 
 /// Custom command-line parsing structure
 #[derive(Parser, Debug)]
@@ -23,39 +30,150 @@ struct Cli {
     )]
     port: u16,
     /// Root directory of the configuration file (support `~` expand)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config_dir: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or(EnvFilter::new("info")),
-        )
-        .with(tracing_subscriber::fmt::layer().pretty())
-        .init();
-    let cli = Cli::parse();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the encrypted key store next to the config,
+    /// whose keys are referred to as `"keystore:NAME"` in `api_key`
+    Keys {
+        /// A file whose content is the passphrase of the key store
+        #[arg(long)]
+        key_file: Option<String>,
+        #[command(subcommand)]
+        action: KeysAction,
+    },
+    /// Inspect the config
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Report every problem of the config, such as a model referring to an unknown api_key
+    Check,
+    /// Print the JSON Schema of the config, for editors to complete and validate it
+    Schema,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysAction {
+    /// Add or replace a key, read from the terminal or stdin
+    Add {
+        /// The name to refer to the key by
+        name: String,
+    },
+    /// List the names of the keys
+    List,
+    /// Remove a key
+    Remove {
+        /// The name of the key
+        name: String,
+    },
+}
+
+fn read_secret() -> anyhow::Result<String> {
+    let secret = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password("Key: ")?
+    } else {
+        std::io::read_to_string(std::io::stdin())?
+    };
+    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+    if secret.is_empty() {
+        bail!("The key is empty");
+    }
+    Ok(secret)
+}
+
+fn run_keys(key_store: KeyStore, action: KeysAction) -> anyhow::Result<()> {
+    let mut keys = key_store.load()?;
+    match action {
+        KeysAction::Add { name } => {
+            let secret = read_secret()?;
+            let replaced = keys.insert(name.clone(), secret).is_some();
+            key_store.save(&keys)?;
+            let verb = if replaced { "Replaced" } else { "Added" };
+            println!("{verb} {name}, refer to it as \"keystore:{name}\"");
+        }
+        KeysAction::List => {
+            for name in keys.keys() {
+                println!("{name}");
+            }
+        }
+        KeysAction::Remove { name } => {
+            keys.remove(&name)
+                .with_context(|| format!("{name} is not in {:?}", key_store.path()))?;
+            key_store.save(&keys)?;
+            println!("Removed {name}");
+        }
+    }
+    Ok(())
+}
+
+fn check_config(config_path: &Path) -> anyhow::Result<()> {
+    let (problems, warnings) = UniModelsInfo::check(config_path)?;
+    for warning in &warnings {
+        println!("warning: {warning}");
+    }
+    if problems.is_empty() {
+        println!("{config_path:?} is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{problem}");
+    }
+    bail!("Found {} problems in {config_path:?}", problems.len());
+}
 
-    let config_dir = cli
-        .config_dir
-        .map(|p| PathBuf::from(tilde(&p).into_owned()))
-        .unwrap_or_else(|| {
-            dirs::home_dir()
-                .expect("Unable to retrieve the user's home directory")
-                .join(".uni-llm")
-        });
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let config_dir = match cli.config_dir {
+        Some(p) => PathBuf::from(tilde(&p).into_owned()),
+        None => dirs::home_dir()
+            .context("Unable to retrieve the user's home directory")?
+            .join(".uni-llm"),
+    };
 
+    let mut config_paths = ConfigFormat::FILE_NAMES
+        .iter()
+        .map(|name| config_dir.join(name))
+        .filter(|path| path.exists());
+    let config_path = config_paths
+        .next()
+        .unwrap_or_else(|| config_dir.join(ConfigFormat::FILE_NAMES[0]));
+    if let Some(ignored) = config_paths.next() {
+        tracing::warn!(
+            "Use {config_path:?} and ignore {ignored:?} in the same directory"
+        );
+    }
+    if let Some(Command::Config { action }) = cli.command {
+        return match action {
+            ConfigAction::Check => check_config(&config_path),
+            ConfigAction::Schema => {
+                let schema = UniModelsInfo::json_schema();
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                Ok(())
+            }
+        };
+    }
     if !config_dir.exists() {
         std::fs::create_dir_all(&config_dir)
-            .unwrap_or_else(|_| panic!("Unable to create directory: {:?}", config_dir));
+            .with_context(|| format!("Unable to create directory: {config_dir:?}"))?;
         tracing::info!(
             "Configuration directory automatically created: {:?}",
             config_dir
         );
     }
-    let config_path = config_dir.join("config.json");
+    if let Some(Command::Keys { key_file, action }) = cli.command {
+        let key_file = key_file.map(|p| PathBuf::from(tilde(&p).into_owned()));
+        let key_store = KeyStore::new(config_dir.join(KEY_STORE_FILE), key_file);
+        return run_keys(key_store, action);
+    }
     if !config_path.exists() {
         let config = UniModelsInfo::default();
         let writer = OpenOptions::new()
@@ -63,20 +181,28 @@ async fn main() {
             .truncate(true)
             .write(true)
             .open(&config_path)
-            .expect("Unable to create config file");
+            .with_context(|| format!("Unable to create config file {config_path:?}"))?;
         serde_json::to_writer_pretty(writer, &config)
-            .expect("Unable to serialize config");
-        return;
+            .context("Unable to serialize config")?;
+        tracing::info!(
+            "Wrote a default config to {config_path:?}, fill it in and start again"
+        );
+        return Ok(());
     }
-    let reader = OpenOptions::new()
-        .read(true)
-        .open(&config_path)
-        .expect("Unable to read config file");
-    let mut models_info: UniModelsInfo =
-        serde_json::from_reader(reader).expect("Unable to parse config file");
-    models_info.insert_latest_tag_for_openwebui();
+    run_server_with_reload(config_path, format!("0.0.0.0:{}", cli.port)).await
+}
 
-    run_server(models_info, format!("0.0.0.0:{}", cli.port))
-        .await
-        .expect("Run Server error");
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or(EnvFilter::new("info")),
+        )
+        .with(tracing_subscriber::fmt::layer().pretty())
+        .init();
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("Error: {e:#}");
+        std::process::exit(1);
+    }
 }
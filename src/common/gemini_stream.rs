@@ -19,6 +19,7 @@ use crate::api::provider::google::gen_ollama_message;
 use crate::api::provider::google::GeminiResponse;
 use crate::api::uni_ollama::message::RespMessage;
 use crate::api::uni_ollama::message::Role;
+use crate::common::tokenizer::UsageEstimator;
 
 #[derive(Debug)]
 enum ChatRespStatus {
@@ -33,6 +34,10 @@ struct OllamaBytesState<S> {
     model_id: String,
     ins: Instant,
     inner: S,
+    /// Used when Gemini does not report the usage
+    estimator: UsageEstimator,
+    /// The generated text so far
+    completion: String,
 }
 
 type ReqwestResult = reqwest::Result<Bytes>;
@@ -83,11 +88,13 @@ impl<S: Stream<Item = ReqwestResult> + Unpin> OllamaBytesState<S> {
 
                 macro_rules! append_msg {
                     ($msg:expr) => {{
+                        let content = $msg;
+                        self.completion.push_str(&content);
                         let msg = gen_ollama_message(
                             &self.model_id,
                             RespMessage {
                                 role: Role::Assistant,
-                                content: $msg,
+                                content,
                                 images: None,
                             },
                         );
@@ -108,7 +115,10 @@ impl<S: Stream<Item = ReqwestResult> + Unpin> OllamaBytesState<S> {
                         } else {
                             let dur = self.ins.elapsed().as_millis() as u32;
                             append_msg!(text);
-                            append_msg!(response.usage_metadata, dur + 1);
+                            let usage = response
+                                .usage_metadata
+                                .or_estimate(&self.estimator, &self.completion);
+                            append_msg!(usage, dur + 1);
                             tracing::info!("finished chatting: chunk:{chunk_str}");
                             self.status = ChatRespStatus::ChatFinished;
                         }
@@ -165,6 +175,7 @@ impl<
 pub(crate) fn get_ollama_stream<S: Stream<Item = ReqwestResult> + Unpin + 'static>(
     model_id: String,
     bytes_stream: S,
+    estimator: UsageEstimator,
 ) -> impl Stream<Item = anyhow::Result<Bytes>> {
     OllamaBytesStream {
         inner: futures::stream::unfold(
@@ -173,6 +184,8 @@ pub(crate) fn get_ollama_stream<S: Stream<Item = ReqwestResult> + Unpin + 'stati
                 model_id,
                 inner: bytes_stream,
                 ins: Instant::now(),
+                estimator,
+                completion: String::new(),
             },
             OllamaBytesState::poll_next,
         ),
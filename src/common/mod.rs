@@ -1,3 +1,14 @@
 //! Some common utilities for the Uni-LLM-API
 pub(crate) mod gemini_stream;
 pub(crate) mod stream;
+pub(crate) mod tokenizer;
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    time::Instant,
+};
+
+/// A random number, good enough for jitter and load balancing
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
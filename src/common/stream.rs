@@ -5,7 +5,6 @@ use std::task::Poll;
 use std::time::Instant;
 
 use anyhow::anyhow;
-use anyhow::Context;
 use bytes::Bytes;
 use bytes::BytesMut;
 use futures::stream::Unfold;
@@ -21,6 +20,7 @@ use crate::api::uni_ollama::message::gen_ollama_message;
 use crate::api::uni_ollama::message::gen_ollama_think_end_message;
 use crate::api::uni_ollama::message::gen_ollama_think_start_message;
 use crate::api::uni_ollama::message::RespMessage;
+use crate::common::tokenizer::UsageEstimator;
 
 #[derive(Debug)]
 enum ChatRespStatus {
@@ -40,6 +40,12 @@ struct OllamaBytesState<S> {
     model_id: String,
     ins: Instant,
     inner: S,
+    /// Used when the provider does not report [`Usage`]
+    estimator: UsageEstimator,
+    /// The [`Usage`] reported by the provider, usually in the last chunk
+    usage: Option<Usage>,
+    /// The generated text so far
+    completion: String,
 }
 
 type ReqwestResult = reqwest::Result<Bytes>;
@@ -75,38 +81,42 @@ impl<S: Stream<Item = ReqwestResult> + Unpin> OllamaBytesState<S> {
         tracing::debug!("chunk_str:{chunk_str}");
         let mut resp_chunk_buf = BytesMut::with_capacity(128);
         // Handle SSE format data (possibly multiple events in one chunk)
-        let mut response = ApiResponse::default();
         for line in chunk_str.split('\n') {
             if let Some(event_data) = line.strip_prefix("data: ") {
                 // Check the end tag
                 if event_data.trim() == "[DONE]" {
                     tracing::info!("DONE completion with chunk:\n {chunk_str}");
                     self.status = ChatRespStatus::ChatFinished;
+                    let usage = self.estimator.fill(self.usage.take(), &self.completion);
                     let msg = gen_last_message(
                         &self.model_id,
                         None,
-                        &response.usage.unwrap_or(Usage::default()),
+                        &usage,
                         self.ins.elapsed().as_millis() as u32,
                     );
                     resp_chunk_buf.extend_from_slice(msg.as_bytes());
                     resp_chunk_buf.extend_from_slice(b"\n");
                     break;
-                } else {
-                    // Parse JSON
-                    response = serde_json::from_str::<ApiResponse>(event_data)?;
+                }
+                // Parse JSON
+                let mut response = serde_json::from_str::<ApiResponse>(event_data)?;
+                if response.usage.is_some() {
+                    self.usage = response.usage.take();
                 }
 
-                let choice = response
-                    .choices
-                    .first()
-                    .context("choices.first() never emtpy")?;
+                // Some providers report usage in a trailing chunk without choices
+                let Some(choice) = response.choices.first() else {
+                    continue;
+                };
                 macro_rules! append_msg {
                     ($msg:expr) => {{
+                        let content = $msg;
+                        self.completion.push_str(&content);
                         let msg = gen_ollama_message(
                             &self.model_id,
                             RespMessage {
                                 role: choice.delta.role,
-                                content: $msg,
+                                content,
                                 images: None,
                             },
                         );
@@ -232,6 +242,7 @@ impl<
 pub(crate) fn get_ollama_stream<S: Stream<Item = ReqwestResult> + Unpin + 'static>(
     model_id: String,
     bytes_stream: S,
+    estimator: UsageEstimator,
 ) -> impl Stream<Item = anyhow::Result<Bytes>> {
     OllamaBytesStream {
         inner: futures::stream::unfold(
@@ -240,6 +251,9 @@ pub(crate) fn get_ollama_stream<S: Stream<Item = ReqwestResult> + Unpin + 'stati
                 model_id,
                 inner: bytes_stream,
                 ins: Instant::now(),
+                estimator,
+                usage: None,
+                completion: String::new(),
             },
             OllamaBytesState::poll_next,
         ),
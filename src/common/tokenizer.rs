@@ -0,0 +1,254 @@
+//! Local token estimation, used when a provider omits usage and for context length checks
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parking_lot::Mutex;
+use shellexpand::tilde;
+use tiktoken_rs::CoreBPE;
+use tiktoken_rs::Rank;
+
+use crate::api::provider::message::Usage;
+use crate::api::uni_ollama::config::TokenizerKind;
+use crate::api::uni_ollama::message::ReqMessage;
+
+/// Every message is wrapped with a few control tokens,
+/// such as `<|im_start|>{role}\n{content}<|im_end|>`
+const TOKENS_PER_MESSAGE: usize = 4;
+/// Tokens used to prime the reply of the assistant
+pub(crate) const TOKENS_PER_REPLY: usize = 3;
+/// The pre-tokenization pattern shared by `cl100k_base` and most tiktoken-style vocabularies
+const TIKTOKEN_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Counts the tokens of a piece of text
+pub(crate) trait Tokenizer: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Falls back to a character heuristic when no vocabulary is available:
+/// CJK text is roughly one token per character, while other text averages four characters per token
+struct HeuristicTokenizer;
+
+/// Whether `c` is a CJK ideograph, kana, hangul or full-width form
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3000..=0x30FF
+            | 0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xAC00..=0xD7AF
+            | 0xF900..=0xFAFF
+            | 0xFF00..=0xFFEF
+            | 0x20000..=0x2FA1F
+    )
+}
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let (cjk, others) = text.chars().fold((0, 0), |(cjk, others), c| {
+            if is_cjk(c) {
+                (cjk + 1, others)
+            } else {
+                (cjk, others + 1)
+            }
+        });
+        cjk + usize::div_ceil(others, 4)
+    }
+}
+
+/// A tiktoken-style byte pair encoding
+struct BpeTokenizer(CoreBPE);
+
+impl Tokenizer for BpeTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.0.encode_ordinary(text).len()
+    }
+}
+
+/// A tokenizer once it is loaded, or why it could not be
+type LoadedTokenizer = Result<Arc<dyn Tokenizer>, String>;
+
+/// Loaded tokenizers, the vocabularies are large so each one is only built once.
+/// A failed load is kept as well, so that it is not retried on every request
+static TOKENIZERS: LazyLock<
+    Mutex<HashMap<TokenizerKind, Arc<OnceLock<LoadedTokenizer>>>>,
+> = LazyLock::new(Default::default);
+
+fn load_tokenizer(kind: &TokenizerKind) -> anyhow::Result<Arc<dyn Tokenizer>> {
+    Ok(match kind {
+        TokenizerKind::Heuristic => Arc::new(HeuristicTokenizer),
+        TokenizerKind::Cl100kBase => Arc::new(BpeTokenizer(tiktoken_rs::cl100k_base()?)),
+        TokenizerKind::O200kBase => Arc::new(BpeTokenizer(tiktoken_rs::o200k_base()?)),
+        TokenizerKind::Tiktoken(path) => Arc::new(BpeTokenizer(
+            load_tiktoken_file(path)
+                .with_context(|| format!("Load tiktoken file: {path}"))?,
+        )),
+    })
+}
+
+/// Get the tokenizer of `kind`, loading it on first use.
+/// The vocabulary is built outside of the global lock, so that other kinds are not blocked
+pub(crate) fn get_tokenizer(kind: &TokenizerKind) -> anyhow::Result<Arc<dyn Tokenizer>> {
+    let cell = TOKENIZERS.lock().entry(kind.clone()).or_default().clone();
+    cell.get_or_init(|| match load_tokenizer(kind) {
+        Ok(tokenizer) => {
+            tracing::info!("Tokenizer loaded: {kind:?}");
+            Ok(tokenizer)
+        }
+        Err(e) => {
+            tracing::warn!("Tokenizer {kind:?} failed to load: {e:#}");
+            Err(format!("{e:#}"))
+        }
+    })
+    .clone()
+    .map_err(anyhow::Error::msg)
+}
+
+/// Get the tokenizer of `kind` without blocking the runtime while its vocabulary is built.
+/// An estimate never fails a request, so a tokenizer that cannot be loaded
+/// is replaced by [`TokenizerKind::Heuristic`]
+pub(crate) async fn tokenizer_or_heuristic(kind: &TokenizerKind) -> Arc<dyn Tokenizer> {
+    let loaded = TOKENIZERS
+        .lock()
+        .get(kind)
+        .and_then(|cell| cell.get().cloned());
+    let loaded = match loaded {
+        Some(loaded) => loaded.map_err(anyhow::Error::msg),
+        None => {
+            let loading = kind.clone();
+            tokio::task::spawn_blocking(move || get_tokenizer(&loading))
+                .await
+                .unwrap_or_else(|e| Err(e.into()))
+        }
+    };
+    loaded.unwrap_or_else(|e| {
+        tracing::warn!(
+            "Estimate tokens heuristically, the tokenizer {kind:?} is unavailable: {e:#}"
+        );
+        Arc::new(HeuristicTokenizer)
+    })
+}
+
+/// Load a `.tiktoken` vocabulary, where each line is `{base64 token} {rank}`
+fn load_tiktoken_file(path: &str) -> anyhow::Result<CoreBPE> {
+    let content = std::fs::read_to_string(tilde(path).as_ref())?;
+    let mut encoder = rustc_hash::FxHashMap::default();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        let (token, rank) = line
+            .split_once(' ')
+            .with_context(|| format!("Invalid line: {line}"))?;
+        let rank: Rank = rank.trim().parse()?;
+        encoder.insert(STANDARD.decode(token)?, rank);
+    }
+    CoreBPE::new(encoder, Default::default(), TIKTOKEN_PATTERN)
+}
+
+/// Estimate the tokens of a single message, including its control tokens
+pub(crate) fn count_message_tokens(tokenizer: &dyn Tokenizer, msg: &ReqMessage) -> usize {
+    let tool_calls = msg.tool_calls.iter().flatten().fold(0, |acc, call| {
+        acc + tokenizer.count_tokens(&call.function.name)
+            + tokenizer.count_tokens(&call.function.arguments.to_string())
+    });
+    TOKENS_PER_MESSAGE + tokenizer.count_tokens(&msg.content) + tool_calls
+}
+
+/// Estimate the prompt tokens of a whole conversation
+pub(crate) fn count_prompt_tokens(
+    tokenizer: &dyn Tokenizer,
+    messages: &[ReqMessage],
+) -> usize {
+    messages
+        .iter()
+        .map(|msg| count_message_tokens(tokenizer, msg))
+        .sum::<usize>()
+        + TOKENS_PER_REPLY
+}
+
+/// Fill in the [`Usage`] for providers that do not report it
+#[derive(Clone)]
+pub(crate) struct UsageEstimator {
+    tokenizer: Arc<dyn Tokenizer>,
+    prompt_tokens: u32,
+}
+
+impl UsageEstimator {
+    pub(crate) fn new(tokenizer: Arc<dyn Tokenizer>, prompt_tokens: u32) -> Self {
+        Self {
+            tokenizer,
+            prompt_tokens,
+        }
+    }
+
+    pub(crate) fn count_tokens(&self, text: &str) -> u32 {
+        self.tokenizer.count_tokens(text) as u32
+    }
+
+    /// The estimator of the same prompt with `tokens` more
+    pub(crate) fn with_more_prompt(&self, tokens: u32) -> Self {
+        Self {
+            tokenizer: self.tokenizer.clone(),
+            prompt_tokens: self.prompt_tokens + tokens,
+        }
+    }
+
+    pub(crate) fn estimate(&self, completion: &str) -> Usage {
+        let completion_tokens = self.tokenizer.count_tokens(completion) as u32;
+        Usage {
+            completion_tokens,
+            prompt_tokens: self.prompt_tokens,
+            total_tokens: self.prompt_tokens + completion_tokens,
+        }
+    }
+
+    /// Returns `usage` if the provider reported it, otherwise a local estimate
+    pub(crate) fn fill(&self, usage: Option<Usage>, completion: &str) -> Usage {
+        match usage {
+            Some(usage) if usage.total_tokens > 0 => usage,
+            _ => {
+                tracing::debug!("Missing usage, estimating it locally");
+                self.estimate(completion)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::uni_ollama::config::TokenizerKind;
+
+    use super::{get_tokenizer, tokenizer_or_heuristic, HeuristicTokenizer, Tokenizer};
+
+    #[test]
+    fn test_heuristic() {
+        assert_eq!(HeuristicTokenizer.count_tokens(""), 0);
+        assert_eq!(HeuristicTokenizer.count_tokens("hello world!"), 3);
+        assert_eq!(HeuristicTokenizer.count_tokens("你好世界"), 4);
+        assert_eq!(HeuristicTokenizer.count_tokens("héllo wörld!"), 3);
+    }
+
+    #[test]
+    fn test_bpe() {
+        let tokenizer = get_tokenizer(&TokenizerKind::Cl100kBase).unwrap();
+        assert_eq!(tokenizer.count_tokens("hello world"), 2);
+
+        let missing = TokenizerKind::Tiktoken("/nonexistent/vocab.tiktoken".to_string());
+        let first = get_tokenizer(&missing).err().unwrap().to_string();
+        let second = get_tokenizer(&missing).err().unwrap().to_string();
+        assert!(first.starts_with("Load tiktoken file"));
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let missing =
+            TokenizerKind::Tiktoken("/nonexistent/fallback.tiktoken".to_string());
+        let tokenizer = tokenizer_or_heuristic(&missing).await;
+        assert_eq!(tokenizer.count_tokens("hello world!"), 3);
+        let tokenizer = tokenizer_or_heuristic(&TokenizerKind::Cl100kBase).await;
+        assert_eq!(tokenizer.count_tokens("hello world"), 2);
+    }
+}
@@ -1,24 +1,64 @@
 //! implements the API for the Uni Llama project
-use api::uni_ollama::{chat::api_chat, config::UniModelInfoRef};
+use api::client::ClientCache;
+use api::uni_ollama::{
+    balance::Balancer,
+    cache::ResponseCache,
+    chat::api_chat,
+    coalesce::SingleFlight,
+    config::UniModelInfoRef,
+    gate::Gates,
+    reload::{self, ConfigFile},
+    summarize::SummaryCache,
+};
 use axum::Json;
+use middleware::admin_auth::AdminAuthLayer;
 use middleware::cors::CorsLayer;
+use middleware::rate_limit::RateLimitLayer;
 use parking_lot::RwLock;
-use reqwest::Client;
-use reqwest::ClientBuilder;
-use reqwest::Proxy;
 use serde_json::json;
 use serde_json::Value;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::ToSocketAddrs;
 use tower_http::trace::DefaultMakeSpan;
 use tower_http::trace::TraceLayer;
 
+use api::uni_ollama::admin::{
+    api_admin_delete_api_key, api_admin_delete_model, api_admin_disable_key,
+    api_admin_enable_key, api_admin_get_api_key, api_admin_get_model, api_admin_keys,
+    api_admin_list_api_keys, api_admin_list_models, api_admin_patch_api_key,
+    api_admin_put_api_key, api_admin_put_model, api_admin_reset_key,
+};
+pub use api::uni_ollama::config::AdminConfig;
 pub use api::uni_ollama::config::ApiKeyInfo;
 pub use api::uni_ollama::config::ApiKeyProvider;
+pub use api::uni_ollama::config::BalanceStrategy;
+pub use api::uni_ollama::config::CacheConfig;
+pub use api::uni_ollama::config::CircuitBreakerConfig;
+pub use api::uni_ollama::config::ConcurrencyConfig;
+pub use api::uni_ollama::config::ContinuationMode;
+pub use api::uni_ollama::config::HistoryPolicy;
+pub use api::uni_ollama::config::ModelCacheConfig;
 pub use api::uni_ollama::config::ModelInfo;
+pub use api::uni_ollama::config::ModelTarget;
+pub use api::uni_ollama::config::RateLimit;
+pub use api::uni_ollama::config::RateLimitConfig;
+pub use api::uni_ollama::config::RetryPolicy;
+pub use api::uni_ollama::config::RetryableError;
+pub use api::uni_ollama::config::SummarizeConfig;
+pub use api::uni_ollama::config::TimeoutConfig;
+pub use api::uni_ollama::config::TlsConfig;
+pub use api::uni_ollama::config::TokenizerKind;
 pub use api::uni_ollama::config::UniModelsInfo;
+pub use api::uni_ollama::format::ConfigFormat;
+pub use api::uni_ollama::keystore::KeyStore;
+pub use api::uni_ollama::keystore::KEY_FILE_ENV;
+pub use api::uni_ollama::keystore::KEY_STORE_FILE;
+pub use api::uni_ollama::keystore::PASSPHRASE_ENV;
 use api::uni_ollama::tag::api_tags;
+use api::uni_ollama::tokenize::api_tokenize;
 use axum::{
     routing::{get, post},
     Router,
@@ -29,38 +69,67 @@ pub mod common;
 pub mod middleware;
 
 pub(crate) struct SharedState {
-    pub proxy_client: Option<Client>,
-    pub client: Client,
+    pub clients: ClientCache,
     pub model_config: UniModelInfoRef,
+    pub summaries: SummaryCache,
+    pub balancer: Balancer,
+    pub gates: Gates,
+    pub flights: SingleFlight,
+    pub cache: ResponseCache,
+    pub config_file: ConfigFile,
 }
 
 pub(crate) type SharedStateRef = std::sync::Arc<SharedState>;
 
-/// Run the server
+/// Run the server, resolving the secret references of `init_models_info`
 pub async fn run_server<A: ToSocketAddrs + Debug>(
     init_models_info: UniModelsInfo,
     addr: A,
 ) -> anyhow::Result<()> {
-    let client = ClientBuilder::new().no_proxy().build()?;
-    let proxy_client = init_models_info.proxy_url.as_ref().map(|url| {
-        ClientBuilder::new()
-            .proxy(
-                Proxy::https(url)
-                    .expect("proxy url must be valid when crate Proxy::https"),
-            )
-            .proxy(
-                Proxy::http(url).expect("proxy url must be valid when crate Proxy::http"),
-            )
-            .build()
-            .expect("construct proxy client nerver fails")
-    });
-
-    let model_config = UniModelInfoRef::new(RwLock::new(init_models_info));
-    let shared_state = SharedState {
-        client,
-        model_config,
-        proxy_client,
-    };
+    let mut raw = init_models_info;
+    // The tags are added again once it is resolved
+    raw.remove_latest_tags();
+    let config_file = ConfigFile::new(None, raw, None);
+    let served = config_file.read(|raw| config_file.resolve(raw))?;
+    serve(new_state(served, config_file), addr).await
+}
+
+/// Run the server with the config file at `config_path` and the [`KeyStore`] next to it,
+/// reloading them on SIGHUP and whenever they change
+pub async fn run_server_with_reload<A: ToSocketAddrs + Debug>(
+    config_path: PathBuf,
+    addr: A,
+) -> anyhow::Result<()> {
+    let raw = UniModelsInfo::read(&config_path)?;
+    let key_store = KeyStore::new(config_path.with_file_name(KEY_STORE_FILE), None);
+    let config_file = ConfigFile::new(Some(config_path.clone()), raw, Some(key_store));
+    let served = config_file.read(|raw| config_file.resolve(raw))?;
+    // Reloads and admin writes resolve it again, away from the terminal
+    config_file.stop_prompting();
+    let state = new_state(served, config_file);
+    reload::watch(state.clone(), config_path)?;
+    serve(state, addr).await
+}
+
+fn new_state(init_models_info: UniModelsInfo, config_file: ConfigFile) -> SharedStateRef {
+    let clients = ClientCache::new(&init_models_info);
+    let cache = ResponseCache::new(init_models_info.cache.as_ref());
+    Arc::new(SharedState {
+        clients,
+        model_config: UniModelInfoRef::new(RwLock::new(init_models_info)),
+        summaries: SummaryCache::default(),
+        balancer: Balancer::default(),
+        gates: Gates::default(),
+        flights: SingleFlight::default(),
+        cache,
+        config_file,
+    })
+}
+
+/// The routes of the server. Every admin route, including the ones that only read,
+/// is behind [`AdminAuthLayer`]
+fn router(shared_state: SharedStateRef) -> Router {
+    let rate_limit = RateLimitLayer::new(shared_state.model_config.clone());
 
     async fn api_version() -> Json<Value> {
         Json(json!({
@@ -68,22 +137,131 @@ pub async fn run_server<A: ToSocketAddrs + Debug>(
         }))
     }
 
+    let admin_routes = Router::new()
+        .route("/keys", get(api_admin_keys))
+        .route(
+            "/keys/{api_key_id}/{index}/reset",
+            post(api_admin_reset_key),
+        )
+        .route("/api_keys", get(api_admin_list_api_keys))
+        .route(
+            "/api_keys/{api_key_id}",
+            get(api_admin_get_api_key)
+                .put(api_admin_put_api_key)
+                .patch(api_admin_patch_api_key)
+                .delete(api_admin_delete_api_key),
+        )
+        .route(
+            "/api_keys/{api_key_id}/{index}/enable",
+            post(api_admin_enable_key),
+        )
+        .route(
+            "/api_keys/{api_key_id}/{index}/disable",
+            post(api_admin_disable_key),
+        )
+        .route("/models", get(api_admin_list_models))
+        .route(
+            "/models/{*model_id}",
+            get(api_admin_get_model)
+                .put(api_admin_put_model)
+                .delete(api_admin_delete_model),
+        )
+        .layer(AdminAuthLayer::new(shared_state.model_config.clone()));
+
     let api_routes: Router = Router::new()
         .route("/tags", get(api_tags))
-        .route("/chat", post(api_chat))
+        .route("/chat", post(api_chat).layer(rate_limit))
+        .route("/tokenize", post(api_tokenize))
         .route("/version", get(api_version))
-        .with_state(Arc::new(shared_state));
+        .nest("/admin", admin_routes)
+        .with_state(shared_state);
 
-    let app = Router::new()
+    Router::new()
         .nest("/api", api_routes) // logging so we can see whats going on
         .layer(CorsLayer {})
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
+        )
+}
 
+async fn serve<A: ToSocketAddrs + Debug>(
+    shared_state: SharedStateRef,
+    addr: A,
+) -> anyhow::Result<()> {
+    let app = router(shared_state);
     let listener = tokio::net::TcpListener::bind(addr).await?;
     tracing::info!("Listening on: {:?}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::{api::mock, AdminConfig, UniModelsInfo};
+
+    use super::router;
+
+    #[tokio::test]
+    async fn test_admin_routes_need_token() {
+        let mut config = UniModelsInfo {
+            api_keys: HashMap::from([(
+                "mock".to_string(),
+                mock::api_key(&mock::closed_url().await),
+            )]),
+            ..Default::default()
+        };
+        let status =
+            |config: UniModelsInfo, method: &str, uri: &str, token: Option<&str>| {
+                let app = router(mock::test_state(config));
+                let mut req = Request::builder().method(method).uri(uri);
+                if let Some(token) = token {
+                    req = req.header("authorization", format!("Bearer {token}"));
+                }
+                async move {
+                    app.oneshot(req.body(Body::empty()).unwrap())
+                        .await
+                        .unwrap()
+                        .status()
+                }
+            };
+        let reset = "/api/admin/keys/mock/0/reset";
+        // Disabled without a token in the config
+        assert_eq!(
+            status(config.clone(), "GET", "/api/admin/keys", None).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(config.clone(), "POST", reset, None).await,
+            StatusCode::FORBIDDEN
+        );
+
+        config.admin = Some(AdminConfig {
+            token: "secret".to_string(),
+        });
+        for (method, uri) in [("GET", "/api/admin/keys"), ("POST", reset)] {
+            for token in [None, Some("wrong")] {
+                assert_eq!(
+                    status(config.clone(), method, uri, token).await,
+                    StatusCode::UNAUTHORIZED
+                );
+            }
+            assert_eq!(
+                status(config.clone(), method, uri, Some("secret")).await,
+                StatusCode::OK
+            );
+        }
+    }
+}
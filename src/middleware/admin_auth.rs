@@ -0,0 +1,109 @@
+//! Middleware for authenticating the admin api, see [`crate::UniModelsInfo::admin`]
+
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Request, Response, StatusCode,
+    },
+};
+use futures::future;
+use serde_json::json;
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+use crate::api::uni_ollama::config::UniModelInfoRef;
+
+/// Compare without leaking the length of the common prefix through timing
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// An error in the error format of ollama
+fn reject(status: StatusCode, error: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "error": error }).to_string()))
+        .expect("Construct response nerver fails")
+}
+
+/// A middleware rejecting requests without the admin token
+#[derive(Clone)]
+pub(crate) struct AdminAuthMiddleware<S> {
+    inner: S,
+    model_config: UniModelInfoRef,
+}
+
+impl<S> Service<Request<Body>> for AdminAuthMiddleware<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let token = self
+            .model_config
+            .read()
+            .admin
+            .as_ref()
+            .map(|admin| admin.token.clone());
+        let mut cloned_inner = self.inner.clone();
+
+        Box::pin(async move {
+            let Some(token) = token else {
+                return Ok(reject(
+                    StatusCode::FORBIDDEN,
+                    "The admin api is disabled, set `admin.token` in the config to enable it",
+                ));
+            };
+            let authorized = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .is_some_and(|v| token_eq(v.as_bytes(), token.as_bytes()));
+            if !authorized {
+                tracing::warn!("Rejected an admin request to {}", req.uri().path());
+                return Ok(reject(StatusCode::UNAUTHORIZED, "Invalid admin token"));
+            }
+            cloned_inner.call(req).await
+        })
+    }
+}
+
+/// Layer Implementation for [`AdminAuthMiddleware`]
+#[derive(Clone)]
+pub(crate) struct AdminAuthLayer {
+    model_config: UniModelInfoRef,
+}
+
+impl AdminAuthLayer {
+    pub(crate) fn new(model_config: UniModelInfoRef) -> Self {
+        Self { model_config }
+    }
+}
+
+impl<S> Layer<S> for AdminAuthLayer {
+    type Service = AdminAuthMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AdminAuthMiddleware {
+            inner,
+            model_config: self.model_config.clone(),
+        }
+    }
+}
@@ -71,7 +71,9 @@ where
                     .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
                 response.headers_mut().insert(
                     header::ACCESS_CONTROL_EXPOSE_HEADERS,
-                    HeaderValue::from_static("Content-Length, X-Custom-Header"),
+                    HeaderValue::from_static(
+                        "Content-Length, X-Custom-Header, X-Uni-Llm-Dropped-Messages, X-Uni-Llm-Summarized-Messages, X-Uni-Llm-Coalesced, X-Uni-Llm-Cache, Retry-After",
+                    ),
                 );
             }
 
@@ -1,3 +1,5 @@
 //! Middleware for axum applications
 
+pub(crate) mod admin_auth;
 pub mod cors;
+pub(crate) mod rate_limit;
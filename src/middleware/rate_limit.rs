@@ -0,0 +1,472 @@
+//! Middleware for limiting the requests and tokens per minute, see [`crate::RateLimitConfig`]
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, FromRequest},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap, Request, Response, StatusCode,
+    },
+    response::IntoResponse,
+};
+use futures::{future, StreamExt};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+use crate::{
+    api::uni_ollama::{
+        config::{RateLimit, TokenizerKind, UniModelInfoRef},
+        health::mask_secret,
+        history::PromptTokens,
+        message::OllamaChatRequest,
+    },
+    common::tokenizer::tokenizer_or_heuristic,
+};
+
+/// Idle buckets are dropped once there are more than this many
+const MAX_BUCKETS: usize = 10_000;
+
+/// What a limit applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Global,
+    Client(String),
+    Model(String),
+}
+
+/// A token bucket holding up to a minute worth of `per_minute`
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        let capacity = per_minute.max(1) as f64;
+        Self {
+            capacity,
+            available: capacity,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        let refilled = self.available + elapsed.as_secs_f64() * self.capacity / 60.0;
+        self.available = refilled.min(self.capacity);
+    }
+
+    /// How long until `cost` is available, a cost above the capacity only has to wait for a full bucket
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing * 60.0 / self.capacity)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    limit: RateLimit,
+    requests: Option<Bucket>,
+    /// May go below zero when the completion is charged
+    tokens: Option<Bucket>,
+    updated: Instant,
+}
+
+impl Buckets {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            limit: limit.clone(),
+            requests: limit.rpm.map(Bucket::new),
+            tokens: limit.tpm.map(Bucket::new),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now - self.updated;
+        self.updated = now;
+        for bucket in [&mut self.requests, &mut self.tokens].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+    }
+}
+
+struct Limiter {
+    model_config: UniModelInfoRef,
+    buckets: Mutex<HashMap<Scope, Buckets>>,
+}
+
+impl Limiter {
+    /// The limits that apply to a request of `client` for `model`
+    fn limits(&self, client: &str, model: &str) -> Vec<(Scope, RateLimit)> {
+        let guard = self.model_config.read();
+        let Some(config) = &guard.rate_limits else {
+            return Vec::new();
+        };
+        let mut limits = Vec::new();
+        if let Some(limit) = &config.global {
+            limits.push((Scope::Global, limit.clone()));
+        }
+        if let Some(limit) = config.models.get(model) {
+            limits.push((Scope::Model(model.to_string()), limit.clone()));
+        }
+        if let Some(limit) = config.clients.get(client).or(config.per_client.as_ref()) {
+            limits.push((Scope::Client(client.to_string()), limit.clone()));
+        }
+        limits
+    }
+
+    /// Take a request and `tokens` from every limit, or none of them.
+    /// Returns how long to wait if any limit is exhausted
+    fn acquire(
+        &self,
+        limits: &[(Scope, RateLimit)],
+        tokens: f64,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() > MAX_BUCKETS {
+            // Buckets idle for a minute are full again anyway
+            buckets.retain(|_, b| now - b.updated < Duration::from_secs(60));
+        }
+        let mut wait = Duration::ZERO;
+        for (scope, limit) in limits {
+            let entry = buckets
+                .entry(scope.clone())
+                .or_insert_with(|| Buckets::new(limit, now));
+            // The limit was changed by a reload
+            if entry.limit != *limit {
+                *entry = Buckets::new(limit, now);
+            }
+            entry.refill(now);
+            let requests = entry.requests.as_ref().map(|b| b.wait(1.0));
+            let tokens = entry.tokens.as_ref().map(|b| b.wait(tokens));
+            wait = wait.max(requests.max(tokens).unwrap_or_default());
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for (scope, _) in limits {
+            let entry = buckets.get_mut(scope).expect("inserted above");
+            if let Some(bucket) = &mut entry.requests {
+                bucket.available -= 1.0;
+            }
+            if let Some(bucket) = &mut entry.tokens {
+                bucket.available -= tokens;
+            }
+        }
+        Ok(())
+    }
+
+    /// Charge the completion `tokens` of a request that passed [`Self::acquire`]
+    fn charge(&self, scopes: &[Scope], tokens: f64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        for scope in scopes {
+            if let Some(entry) = buckets.get_mut(scope) {
+                entry.refill(now);
+                if let Some(bucket) = &mut entry.tokens {
+                    bucket.available -= tokens;
+                }
+            }
+        }
+    }
+
+    /// Identify the client of a request by its bearer token if it is one of
+    /// [`crate::RateLimitConfig::clients`], or else its address. Other tokens are not trusted,
+    /// as a client could pick a new one for every request to get a fresh bucket
+    fn client(&self, headers: &HeaderMap, addr: Option<SocketAddr>) -> String {
+        let guard = self.model_config.read();
+        let is_known = |token: &&str| {
+            guard
+                .rate_limits
+                .as_ref()
+                .is_some_and(|config| config.clients.contains_key(*token))
+        };
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .filter(is_known)
+            .map(str::to_string)
+            .or_else(|| addr.map(|addr| addr.ip().to_string()))
+            .unwrap_or_default()
+    }
+
+    /// The model and the estimated prompt tokens of a chat request
+    async fn estimate(&self, body: &[u8]) -> Option<(String, PromptTokens)> {
+        let payload = serde_json::from_slice::<OllamaChatRequest>(body).ok()?;
+        let kind = {
+            let guard = self.model_config.read();
+            guard
+                .models
+                .get(&payload.model)
+                .map_or(TokenizerKind::Heuristic, |m| guard.tokenizer_kind(m))
+        };
+        let tokenizer = tokenizer_or_heuristic(&kind).await;
+        let tokens = PromptTokens::count(tokenizer.as_ref(), &payload.messages);
+        Some((payload.model, tokens))
+    }
+}
+
+/// The last line of an ollama response
+#[derive(Deserialize)]
+struct DoneLine {
+    #[serde(default)]
+    done: bool,
+    eval_count: Option<u32>,
+}
+
+/// Charge the completion tokens reported by the last line of `res` once it is done
+fn charge_completion(
+    limiter: Arc<Limiter>,
+    scopes: Vec<Scope>,
+    res: Response<Body>,
+) -> Response<Body> {
+    res.map(|body| {
+        let mut stream = body.into_data_stream();
+        Body::from_stream(async_stream::stream! {
+            let mut last_line = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                if let Ok(chunk) = &chunk {
+                    last_line.extend_from_slice(chunk);
+                    let end = last_line.len().saturating_sub(1);
+                    if let Some(pos) = last_line[..end].iter().rposition(|b| *b == b'\n') {
+                        last_line.drain(..=pos);
+                    }
+                }
+                yield chunk;
+            }
+            let completion = serde_json::from_slice::<DoneLine>(last_line.trim_ascii())
+                .ok()
+                .filter(|line| line.done)
+                .and_then(|line| line.eval_count);
+            if let Some(completion) = completion {
+                limiter.charge(&scopes, completion as f64);
+            }
+        })
+    })
+}
+
+/// A 429 in the error format of ollama
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    let body = json!({ "error": format!("Rate limit exceeded, retry after {secs}s") });
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(RETRY_AFTER, secs)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("Construct response nerver fails")
+}
+
+/// A middleware rejecting chat requests over the [`crate::RateLimitConfig`] with a 429
+#[derive(Clone)]
+pub(crate) struct RateLimitMiddleware<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request<Body>> for RateLimitMiddleware<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let limiter = self.limiter.clone();
+        let mut cloned_inner = self.inner.clone();
+
+        Box::pin(async move {
+            if limiter.model_config.read().rate_limits.is_none() {
+                return cloned_inner.call(req).await;
+            }
+            // The model and the prompt are in the body,
+            // which is read up to the same `DefaultBodyLimit` as the handler
+            let (mut parts, body) = req.into_parts();
+            let mut limited = Request::new(body);
+            limited.extensions_mut().clone_from(&parts.extensions);
+            let body = match Bytes::from_request(limited, &()).await {
+                Ok(body) => body,
+                Err(rejection) => return Ok(rejection.into_response()),
+            };
+            let addr = parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0);
+            let client = limiter.client(&parts.headers, addr);
+            // Invalid requests are left to the handler to reject
+            let (model, prompt_tokens) =
+                limiter.estimate(&body).await.unwrap_or_default();
+            let limits = limiter.limits(&client, &model);
+            if let Err(wait) = limiter.acquire(&limits, prompt_tokens.total() as f64) {
+                tracing::warn!(
+                    "Rate limited client {} on {model}, retry after {wait:?}",
+                    mask_secret(&client)
+                );
+                return Ok(too_many_requests(wait));
+            }
+            // The handler fits the history with the same tokens
+            parts.extensions.insert(prompt_tokens);
+
+            let res = cloned_inner
+                .call(Request::from_parts(parts, Body::from(body)))
+                .await?;
+            let scopes = limits
+                .into_iter()
+                .filter(|(_, limit)| limit.tpm.is_some())
+                .map(|(scope, _)| scope)
+                .collect::<Vec<_>>();
+            if scopes.is_empty() {
+                return Ok(res);
+            }
+            Ok(charge_completion(limiter, scopes, res))
+        })
+    }
+}
+
+/// Layer Implementation for [`RateLimitMiddleware`]
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(model_config: UniModelInfoRef) -> Self {
+        Self {
+            limiter: Arc::new(Limiter {
+                model_config,
+                buckets: Default::default(),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{
+        body::Body,
+        http::{header::AUTHORIZATION, HeaderMap, HeaderValue, Request, StatusCode},
+    };
+    use parking_lot::RwLock;
+    use tower::ServiceExt;
+
+    use crate::{
+        api::{
+            mock,
+            uni_ollama::config::{RateLimit, RateLimitConfig, UniModelsInfo},
+        },
+        router,
+    };
+
+    use super::RateLimitLayer;
+
+    #[test]
+    fn test_rate_limit() {
+        let models_info = UniModelsInfo {
+            rate_limits: Some(RateLimitConfig {
+                global: Some(RateLimit {
+                    rpm: Some(2),
+                    tpm: None,
+                }),
+                per_client: Some(RateLimit {
+                    rpm: None,
+                    tpm: Some(600),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let layer = RateLimitLayer::new(Arc::new(RwLock::new(models_info)));
+        let limiter = layer.limiter;
+
+        let limits = limiter.limits("a", "m");
+        assert!(limiter.acquire(&limits, 500.0).is_ok());
+        // Client a is out of tokens, waiting about 40s for 400 more of its 10 per second
+        let wait = limiter.acquire(&limits, 500.0).unwrap_err();
+        assert!(wait.as_secs() >= 39 && wait.as_secs() <= 40);
+
+        // Client b only shares the global limit, which has one request left
+        let limits = limiter.limits("b", "m");
+        assert!(limiter.acquire(&limits, 500.0).is_ok());
+        assert!(limiter.acquire(&limits, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let limit = RateLimit {
+            rpm: Some(1),
+            tpm: None,
+        };
+        let models_info = UniModelsInfo {
+            rate_limits: Some(RateLimitConfig {
+                clients: HashMap::from([("team-a".to_string(), limit)]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let limiter = RateLimitLayer::new(Arc::new(RwLock::new(models_info))).limiter;
+        let addr = Some("10.0.0.1:1234".parse().unwrap());
+        let client = |token: &str| {
+            let mut headers = HeaderMap::new();
+            let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+            headers.insert(AUTHORIZATION, value);
+            limiter.client(&headers, addr)
+        };
+        assert_eq!(client("team-a"), "team-a");
+        // Made up tokens do not get a bucket of their own
+        assert_eq!(client("random-1"), "10.0.0.1");
+        assert_eq!(client("random-2"), "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let models_info = UniModelsInfo {
+            rate_limits: Some(RateLimitConfig::default()),
+            ..Default::default()
+        };
+        let app = router(mock::test_state(models_info));
+        let body = format!(
+            r#"{{"model":"m","messages":[],"pad":"{}"}}"#,
+            "x".repeat(3 << 20)
+        );
+        let req = Request::post("/api/chat").body(Body::from(body)).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}